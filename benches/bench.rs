@@ -1,14 +1,16 @@
-use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use jpeg_labs::{
+    batch,
     huffman::HuffmanTree,
     mcu::{BitReader, Block, Mcu},
     quantization_table::QuantizationTable,
     start_of_frame_0::{ComponentInfo, StartOfFrameInfo},
+    ColorSpace, Decoder, Image,
 };
 use smallvec::smallvec;
 use std::io::BufReader;
 
-criterion_group!(benches, block, mcu, bitreader);
+criterion_group!(benches, block, mcu, bitreader, decode, batch);
 criterion_main!(benches);
 
 fn block(c: &mut Criterion) {
@@ -16,6 +18,19 @@ fn block(c: &mut Criterion) {
     c.bench_function("idct", |b| b.iter(|| block.idct()));
     c.bench_function("zigzag", |b| b.iter(|| block.zigzag()));
     c.bench_function("dequantize", |b| b.iter(|| block.dequantize(&[1; 64])));
+    let (coefficients, qt) = (black_box(Block([1; 64])), black_box([2; 64]));
+    c.bench_function("dequantize+zigzag", |b| {
+        b.iter(|| coefficients.dequantize(&qt).zigzag())
+    });
+    c.bench_function("dequantize_zigzag", |b| {
+        b.iter(|| coefficients.dequantize_zigzag(&qt))
+    });
+    c.bench_function("dequantize_zigzag+idct", |b| {
+        b.iter(|| coefficients.dequantize_zigzag(&qt).idct())
+    });
+    c.bench_function("idct_dequant", |b| {
+        b.iter(|| coefficients.idct_dequant(&qt))
+    });
     c.bench_function("upsample", |b| b.iter(|| block.upsample_2x2(0, 0)));
 }
 
@@ -24,21 +39,25 @@ fn mcu(c: &mut Criterion) {
         blocks: smallvec![Block([0; 64]); 6],
     };
     let qts = [
-        QuantizationTable {
+        Some(QuantizationTable {
             id: 0,
             values: [1; 64],
-        },
-        QuantizationTable {
+        }),
+        Some(QuantizationTable {
             id: 1,
             values: [1; 64],
-        },
+        }),
+        None,
+        None,
     ];
     let s2 = ComponentInfo {
+        id: 1,
         horizontal_sampling: 2,
         vertical_sampling: 2,
         quant_table_id: 0,
     };
     let s1 = ComponentInfo {
+        id: 2,
         horizontal_sampling: 1,
         vertical_sampling: 1,
         quant_table_id: 1,
@@ -47,12 +66,17 @@ fn mcu(c: &mut Criterion) {
         precision: 8,
         height: 1080,
         width: 1920,
-        component_infos: [s2, s1, s1],
+        component_number: 3,
+        component_infos: smallvec![s2, s1, ComponentInfo { id: 3, ..s1 },],
         max_horizontal_sampling: 2,
         max_vertical_sampling: 2,
     };
     c.bench_function("yuv420_itrans", |b| b.iter(|| mcu.itrans(&sof, &qts)));
     c.bench_function("yuv420_to_rgb", |b| b.iter(|| mcu.to_rgb(&sof)));
+    let mut rgb = mcu.to_rgb(&sof);
+    c.bench_function("yuv420_to_rgb_into", |b| {
+        b.iter(|| mcu.to_rgb_into(&sof, ColorSpace::default(), &mut rgb))
+    });
 
     let mut mcu = Mcu {
         blocks: smallvec![Block([0; 64]); 3],
@@ -61,7 +85,12 @@ fn mcu(c: &mut Criterion) {
         precision: 8,
         height: 1080,
         width: 1920,
-        component_infos: [s1, s1, s1],
+        component_number: 3,
+        component_infos: smallvec![
+            ComponentInfo { id: 1, ..s1 },
+            s1,
+            ComponentInfo { id: 3, ..s1 },
+        ],
         max_horizontal_sampling: 1,
         max_vertical_sampling: 1,
     };
@@ -91,3 +120,47 @@ fn bitreader(c: &mut Criterion) {
         group.bench_function("16", |b| b.iter(|| reader.read_decode_haffman(&huffman)));
     }
 }
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for name in ["autumn", "tree"] {
+        let data = std::fs::read(format!("data/{name}.jpg")).unwrap();
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| Decoder::from_bytes(&data).read().unwrap().decode_to_rgb8())
+        });
+        group.bench_function(format!("{name}/bufreader"), |b| {
+            b.iter(|| {
+                let reader = BufReader::new(&data[..]);
+                Decoder::new(reader).read().unwrap().decode_to_rgb8()
+            })
+        });
+        group.bench_function(format!("{name}/luma"), |b| {
+            b.iter(|| Decoder::from_bytes(&data).read().unwrap().decode_luma8())
+        });
+        group.bench_function(format!("{name}/scaled8"), |b| {
+            b.iter(|| Decoder::from_bytes(&data).read().unwrap().decode_scaled(8))
+        });
+    }
+}
+
+fn batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    // thumbnails
+    let inputs: Vec<Vec<u8>> = (["gray", "comment", "pattern_420", "pattern_422"].iter())
+        .map(|name| std::fs::read(format!("data/{name}.jpg")).unwrap())
+        .cycle()
+        .take(1024)
+        .collect();
+    group.throughput(Throughput::Elements(inputs.len() as u64));
+    group.bench_function("map", |b| {
+        b.iter(|| {
+            (inputs.iter())
+                .map(|data| Image::try_from(&data[..]))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("decode_many", |b| {
+        b.iter(|| batch::decode_many(inputs.clone()))
+    });
+}
@@ -0,0 +1,18 @@
+#!/bin/sh
+# Decode the fixtures by libjpeg into the references of tests/reference.rs,
+# without fancy upsampling and with the accurate integer IDCT.
+#
+# Usage: data/references.sh [FILE.jpg...]
+# Without arguments, the fixtures which already have a reference are decoded again.
+set -e
+cd "$(dirname "$0")"
+if [ $# -eq 0 ]; then
+    for ppm in *.ppm; do
+        [ -f "${ppm%.ppm}.jpg" ] && set -- "$@" "${ppm%.ppm}.jpg"
+    done
+fi
+for jpg in "$@"; do
+    jpg=$(basename "$jpg")
+    djpeg -nosmooth -dct int -pnm -outfile "${jpg%.jpg}.ppm" "$jpg"
+    echo "${jpg%.jpg}.ppm"
+done
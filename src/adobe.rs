@@ -0,0 +1,101 @@
+use crate::{prelude::*, source::Input, Decoder, JpegError, Result};
+use num_enum::TryFromPrimitive;
+use tracing::debug;
+
+/// The Adobe header in APP14.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adobe {
+    pub version: u16,
+    pub transform: ColorTransform,
+}
+
+/// The color transform applied by the encoder.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ColorTransform {
+    /// RGB or CMYK.
+    #[default]
+    None = 0,
+    YCbCr = 1,
+    YCCK = 2,
+}
+
+impl<R: Input> Decoder<R> {
+    /// Read APP14, returning the [`Adobe`] header if present.
+    pub(crate) fn read_app14(&mut self) -> Result<Option<Adobe>> {
+        let len = self.read_u16()?;
+        debug!(len, "read section APP14");
+        let mut data = vec![0; (len as usize).saturating_sub(2)];
+        self.read_exact(&mut data)?;
+        let Some(header) = data.strip_prefix(b"Adobe") else {
+            return Ok(None);
+        };
+        if header.len() < 7 {
+            return Err(JpegError::InvalidData(format!(
+                "invalid Adobe length: {len}"
+            )));
+        }
+        let transform = ColorTransform::try_from(header[6]).map_err(|_| {
+            JpegError::InvalidData(format!("invalid Adobe transform: {}", header[6]))
+        })?;
+        let adobe = Adobe {
+            version: u16::from_be_bytes([header[0], header[1]]),
+            transform,
+        };
+        debug!(?adobe);
+        Ok(Some(adobe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mcu::open, RGB};
+
+    #[test]
+    fn test_adobe() {
+        let adobe = |transform| Adobe {
+            version: 100,
+            transform,
+        };
+        assert_eq!(
+            open("data/cmyk.jpg").adobe(),
+            Some(&adobe(ColorTransform::None))
+        );
+        assert_eq!(
+            open("data/ycck.jpg").adobe(),
+            Some(&adobe(ColorTransform::YCCK))
+        );
+        assert_eq!(open("data/autumn.jpg").adobe(), None);
+    }
+
+    #[test]
+    fn test_no_adobe() {
+        let data = std::fs::read("data/cmyk.jpg").expect("failed to read file");
+        // strip APP14 after SOI
+        let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let stripped = [&data[..2], &data[4 + len..]].concat();
+        let reader = Decoder::new(&stripped[..]).read().unwrap();
+        assert_eq!(reader.adobe(), None);
+        assert_eq!(reader.transform(), ColorTransform::None);
+
+        // the same CMYK, but not inverted
+        let (_, _, cmyk) = Decoder::new(&data[..])
+            .read()
+            .unwrap()
+            .decode_to_cmyk8()
+            .unwrap();
+        let (_, _, rgb) = reader.decode_to_rgb8().unwrap();
+        for (p, rgb) in cmyk.iter().zip(&rgb) {
+            let mul = |x: u8| (((255 - x) as u16 * (255 - p.k) as u16 + 127) / 255) as u8;
+            assert_eq!(
+                *rgb,
+                RGB {
+                    r: mul(p.c),
+                    g: mul(p.m),
+                    b: mul(p.y)
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Decoding many small images in parallel.
+
+use crate::{Decoder, Image, McuRGB, Result};
+use rayon::prelude::*;
+
+/// Decode each JPEG of `inputs` into an [`Image`] on the rayon thread pool,
+/// returning the results in the order of `inputs`.
+///
+/// The buffer of MCUs is reused by all images decoded on the same thread.
+pub fn decode_many(inputs: Vec<Vec<u8>>) -> Vec<Result<Image>> {
+    inputs
+        .par_iter()
+        .map_init(McuRGB::default, |mcu, data| {
+            let reader = Decoder::from_bytes(data).read()?;
+            let (width, height, pixels) = reader.decode_to_rgb8_with(mcu)?;
+            Ok(Image {
+                width,
+                height,
+                pixels,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_many() {
+        let names = ["gray", "city", "pattern_420", "comment", "gray"];
+        let mut inputs: Vec<Vec<u8>> = (names.iter())
+            .map(|name| std::fs::read(format!("data/{name}.jpg")).expect("failed to read file"))
+            .collect();
+        inputs.insert(2, b"not a jpeg".to_vec());
+        let images = decode_many(inputs.clone());
+        assert_eq!(images.len(), inputs.len());
+        for (data, image) in inputs.iter().zip(images) {
+            match Image::try_from(&data[..]) {
+                Ok(expected) => assert_eq!(image.unwrap(), expected),
+                Err(_) => assert!(image.is_err()),
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+
+use crate::decode::RGB;
+
+/// Write 24-bit BMP.
+///
+/// BMP is stored bottom-up, so the pixels are buffered until [`Self::finish`].
+pub struct BmpWriter<W: Write> {
+    writer: BufWriter<W>,
+    pixels: Vec<RGB>,
+    width: usize,
+}
+
+impl<W: Write> BmpWriter<W> {
+    pub fn new(writer: W, width: u32, height: u32) -> Result<Self> {
+        // rows are padded to 4 bytes
+        let image_size = (width as u64 * 3).next_multiple_of(4) * height as u64;
+        let file_size = u32::try_from(14 + 40 + image_size).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{width}x{height} is too large for BMP"),
+            )
+        })?;
+        let mut writer = BufWriter::new(writer);
+        // BITMAPFILEHEADER
+        writer.write_all(b"BM")?;
+        writer.write_all(&file_size.to_le_bytes())?;
+        writer.write_all(&[0; 4])?;
+        writer.write_all(&(14u32 + 40).to_le_bytes())?;
+        // BITMAPINFOHEADER
+        writer.write_all(&40u32.to_le_bytes())?;
+        writer.write_all(&width.to_le_bytes())?;
+        writer.write_all(&height.to_le_bytes())?;
+        // 1 plane, 24 bits per pixel, no compression
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&24u16.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&(image_size as u32).to_le_bytes())?;
+        // 72 DPI, no palette
+        writer.write_all(&2835u32.to_le_bytes())?;
+        writer.write_all(&2835u32.to_le_bytes())?;
+        writer.write_all(&[0; 8])?;
+        Ok(BmpWriter {
+            writer,
+            pixels: Vec::with_capacity(width as usize * height as usize),
+            width: width as usize,
+        })
+    }
+
+    pub fn write_slice(&mut self, pixel: &[RGB]) -> Result<()> {
+        self.pixels.extend_from_slice(pixel);
+        Ok(())
+    }
+
+    /// Write the rows from bottom to top.
+    ///
+    /// The BMP is empty without calling this.
+    pub fn finish(mut self) -> Result<W> {
+        let padding = (self.width * 3).next_multiple_of(4) - self.width * 3;
+        let mut row = Vec::with_capacity(self.width * 3 + padding);
+        for pixels in self.pixels.chunks(self.width).rev() {
+            row.clear();
+            for p in pixels {
+                row.extend_from_slice(&[p.b, p.g, p.r]);
+            }
+            row.resize(self.width * 3 + padding, 0);
+            self.writer.write_all(&row)?;
+        }
+        self.writer.flush()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmp_writer() {
+        let pixels: Vec<RGB> = (0..6)
+            .map(|i| RGB {
+                r: i,
+                g: 2 * i,
+                b: 3 * i,
+            })
+            .collect();
+        let mut writer = BmpWriter::new(vec![], 3, 2).unwrap();
+        writer.write_slice(&pixels[..2]).unwrap();
+        writer.write_slice(&pixels[2..]).unwrap();
+        let bmp = writer.finish().unwrap();
+
+        // 3 pixels per row padded to 12 bytes
+        assert_eq!(bmp.len(), 54 + 12 * 2);
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(&bmp[2..6], &78u32.to_le_bytes());
+        assert_eq!(&bmp[10..14], &54u32.to_le_bytes());
+        assert_eq!(&bmp[18..26], &[3, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(&bmp[28..30], &[24, 0]);
+        // bottom-up in BGR
+        #[rustfmt::skip]
+        assert_eq!(&bmp[54..], &[
+            9, 6, 3, 12, 8, 4, 15, 10, 5, 0, 0, 0,
+            0, 0, 0, 3, 2, 1, 6, 4, 2, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_bmp_writer_too_large() {
+        let err = BmpWriter::new(vec![], 40000, 40000).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -1,23 +1,161 @@
 use crate::{
+    adobe::ColorTransform,
     mcu::{Block, Mcu},
+    prelude::*,
     quantization_table::QuantizationTable,
     start_of_frame_0::StartOfFrameInfo,
 };
 
-/// Minimum Coded Unit in RGB.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct McuRGB {
-    blocks: Vec<[RGB; 64]>,
+/// Minimum Coded Unit in pixels.
+///
+/// The MCU of a grayscale image is stored in one byte per pixel, which is expanded to `P`
+/// by [`Self::pixel`] and [`Self::line`] as it is read.
+#[derive(Debug, Default, Clone)]
+pub struct McuPixels<P> {
+    /// Empty in a grayscale MCU.
+    blocks: Vec<[P; 64]>,
+    /// The samples of a grayscale MCU, or empty.
+    gray: Vec<[u8; 64]>,
     width_blocks: u8,
     height_blocks: u8,
 }
 
-impl McuRGB {
-    pub fn line(&self, h: usize) -> impl Iterator<Item = &[RGB]> + '_ {
+/// The Y, Cb and Cr planes at their own resolutions, without upsampling.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PlanarImage {
+    pub y: Vec<u8>,
+    /// Empty in a grayscale image.
+    pub cb: Vec<u8>,
+    /// Empty in a grayscale image.
+    pub cr: Vec<u8>,
+    /// The width and height of each plane.
+    pub sizes: [(u32, u32); 3],
+}
+
+/// Minimum Coded Unit in RGB.
+pub type McuRGB = McuPixels<RGB>;
+
+/// Minimum Coded Unit in CMYK.
+pub type McuCMYK = McuPixels<CMYK>;
+
+impl<P: Copy + From<u8>> McuPixels<P> {
+    /// The width and height in pixels, including the padding beyond the image.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (
+            8 * self.width_blocks as usize,
+            8 * self.height_blocks as usize,
+        )
+    }
+
+    /// The pixel at column `x` and row `y` in the MCU.
+    ///
+    /// Panics if out of [`Self::dimensions`].
+    pub fn pixel(&self, x: usize, y: usize) -> P {
+        self.get_pixel(x, y).expect("pixel out of MCU")
+    }
+
+    /// The pixel like [`Self::pixel`], or `None` if out of [`Self::dimensions`].
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<P> {
+        let (width, height) = self.dimensions();
+        // SAFETY: in bounds
+        (x < width && y < height).then(|| unsafe { self.pixel_unchecked(x, y) })
+    }
+
+    /// The pixel like [`Self::pixel`], without bounds checking.
+    ///
+    /// # Safety
+    ///
+    /// `x` and `y` must be in [`Self::dimensions`].
+    pub unsafe fn pixel_unchecked(&self, x: usize, y: usize) -> P {
+        let (k, i) = (
+            y / 8 * self.width_blocks as usize + x / 8,
+            y % 8 * 8 + x % 8,
+        );
+        if self.is_gray() {
+            return P::from(*self.gray.get_unchecked(k).get_unchecked(i));
+        }
+        *self.blocks.get_unchecked(k).get_unchecked(i)
+    }
+
+    /// Whether the pixels are stored in one byte each, for a grayscale image.
+    pub fn is_gray(&self) -> bool {
+        !self.gray.is_empty()
+    }
+
+    /// The bytes of the pixels stored.
+    pub fn buffer_size(&self) -> usize {
+        self.blocks.len() * core::mem::size_of::<[P; 64]>() + self.gray.len() * 64
+    }
+
+    /// Store `gray` with the blocks cleared, or the other way round if `gray` is `false`.
+    fn set_gray(&mut self, gray: bool) {
+        if gray {
+            self.blocks.clear();
+        } else {
+            self.gray.clear();
+        }
+    }
+
+    /// The pixels of the `h`-th line, expanded from a grayscale MCU on the fly.
+    pub fn line(&self, h: usize) -> impl Iterator<Item = P> + '_ {
         let wb = self.width_blocks as usize;
-        self.blocks[h / 8 * wb..(h / 8 + 1) * wb]
-            .iter()
-            .map(move |b| &b[h % 8 * 8..(h % 8 + 1) * 8])
+        let (blocks, i) = (h / 8 * wb..(h / 8 + 1) * wb, h % 8 * 8..(h % 8 + 1) * 8);
+        // one of them is empty
+        let (color, gray) = match self.is_gray() {
+            true => (&[][..], &self.gray[blocks]),
+            false => (&self.blocks[blocks], &[][..]),
+        };
+        let j = i.clone();
+        (color.iter().flat_map(move |b| b[i.clone()].iter().copied()))
+            .chain((gray.iter()).flat_map(move |b| b[j.clone()].iter().map(|&y| P::from(y))))
+    }
+
+    /// The `h`-th line like [`Self::line`], of the MCU at column `x0` in an image of `width`,
+    /// with the padding beyond the right edge cropped.
+    pub fn cropped_line(&self, h: usize, width: usize, x0: usize) -> impl Iterator<Item = P> + '_ {
+        self.line(h).take(width.saturating_sub(x0))
+    }
+}
+
+impl<P: Copy + From<u8> + PartialEq> PartialEq for McuPixels<P> {
+    /// Compare the pixels, regardless of how they are stored.
+    fn eq(&self, other: &Self) -> bool {
+        self.dimensions() == other.dimensions()
+            && match (self.is_gray(), other.is_gray()) {
+                (true, true) => self.gray == other.gray,
+                (false, false) => self.blocks == other.blocks,
+                _ => (0..self.dimensions().1).all(|h| self.line(h).eq(other.line(h))),
+            }
+    }
+}
+
+impl<P: Copy + From<u8> + Eq> Eq for McuPixels<P> {}
+
+impl McuCMYK {
+    /// Convert to RGB.
+    ///
+    /// Adobe applications write inverted CMYK, where 0 means full ink.
+    pub fn to_rgb(&self, inverted: bool) -> McuRGB {
+        let blocks = self.blocks.iter().map(|block| {
+            block.map(|p| {
+                let [c, m, y, k] = match inverted {
+                    true => [p.c, p.m, p.y, p.k],
+                    false => [255 - p.c, 255 - p.m, 255 - p.y, 255 - p.k],
+                };
+                let mul = |x: u8| ((x as u16 * k as u16 + 127) / 255) as u8;
+                RGB {
+                    r: mul(c),
+                    g: mul(m),
+                    b: mul(y),
+                }
+            })
+        });
+        McuRGB {
+            blocks: blocks.collect(),
+            width_blocks: self.width_blocks,
+            height_blocks: self.height_blocks,
+            ..Default::default()
+        }
     }
 }
 
@@ -30,96 +168,563 @@ pub struct RGB {
     pub b: u8,
 }
 
+/// A gray level.
+impl From<u8> for RGB {
+    fn from(y: u8) -> Self {
+        RGB { r: y, g: y, b: y }
+    }
+}
+
+// `RGB::as_bytes` relies on this layout.
+const _: () = assert!(core::mem::size_of::<RGB>() == 3 && core::mem::align_of::<RGB>() == 1);
+
+impl RGB {
+    /// View the pixels as bytes in R, G, B order.
+    pub fn as_bytes(pixels: &[RGB]) -> &[u8] {
+        // SAFETY: `RGB` is `repr(C)` with three `u8` fields, so it has size 3, alignment 1
+        // and no padding, and the bytes are in field order regardless of endianness.
+        unsafe { core::slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 3) }
+    }
+}
+
+/// A pixel with 16 bits per channel, e.g. for 16-bit PPM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct RGB16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+}
+
+/// Scaled from 0..=255 to 0..=65535.
+impl From<RGB> for RGB16 {
+    fn from(p: RGB) -> Self {
+        RGB16 {
+            r: p.r as u16 * 257,
+            g: p.g as u16 * 257,
+            b: p.b as u16 * 257,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct CMYK {
+    pub c: u8,
+    pub m: u8,
+    pub y: u8,
+    pub k: u8,
+}
+
+/// A gray level in black ink only, not inverted.
+impl From<u8> for CMYK {
+    fn from(y: u8) -> Self {
+        CMYK {
+            c: 0,
+            m: 0,
+            y: 0,
+            k: 255 - y,
+        }
+    }
+}
+
 impl Mcu {
-    pub fn itrans(&mut self, sof: &StartOfFrameInfo, qts: &[QuantizationTable]) {
+    /// Dequantize and transform the blocks, with the quantization tables indexed by id.
+    pub fn itrans(&mut self, sof: &StartOfFrameInfo, qts: &[Option<QuantizationTable>; 4]) {
         let mut i = 0;
         for component in &sof.component_infos {
-            let qt = &qts[component.quant_table_id as usize].values;
-            for _ in 0..component.horizontal_sampling * component.vertical_sampling {
-                self.blocks[i] = self.blocks[i].dequantize(qt).zigzag().idct();
+            let qt = &qts[component.quant_table_id as usize]
+                .as_ref()
+                .expect("quantization table not defined")
+                .values;
+            for _ in 0..component.blocks_per_mcu() {
+                self.blocks[i] = self.blocks[i].dequantize_zigzag(qt).idct();
                 i += 1;
             }
         }
     }
 
-    pub fn to_rgb(&self, sof: &StartOfFrameInfo) -> McuRGB {
-        let mut blocks = Vec::<[RGB; 64]>::with_capacity(
-            (sof.max_horizontal_sampling * sof.max_vertical_sampling) as usize,
+    /// Transform the blocks like [`Self::itrans`] by [`Block::idct_dequant`], with the
+    /// quantization table of each component in natural order.
+    ///
+    /// Only the first `qts.len()` components are transformed, leaving the others as is.
+    pub fn itrans_dequant(&mut self, sof: &StartOfFrameInfo, qts: &[[u16; 64]]) {
+        let mut blocks = self.blocks.iter_mut();
+        for (component, qt) in sof.component_infos.iter().zip(qts) {
+            for block in blocks.by_ref().take(component.blocks_per_mcu()) {
+                *block = block.idct_dequant(qt);
+            }
+        }
+    }
+
+    /// Transform the luma blocks only like [`Self::itrans`], leaving the chroma blocks as is.
+    pub fn itrans_luma(&mut self, sof: &StartOfFrameInfo, qts: &[Option<QuantizationTable>; 4]) {
+        let component = &sof.component_infos[0];
+        let qt = &qts[component.quant_table_id as usize]
+            .as_ref()
+            .expect("quantization table not defined")
+            .values;
+        for block in &mut self.blocks[..component.blocks_per_mcu()] {
+            *block = block.dequantize_zigzag(qt).idct();
+        }
+    }
+
+    /// Convert the luma blocks to grayscale, ignoring the chroma.
+    ///
+    /// The luma is upsampled if a chroma component has higher sampling factors.
+    pub fn to_luma_into(&self, sof: &StartOfFrameInfo, out: &mut McuPixels<u8>) {
+        out.width_blocks = sof.max_horizontal_sampling;
+        out.height_blocks = sof.max_vertical_sampling;
+        out.set_gray(false);
+        out.blocks.resize(
+            sof.max_horizontal_sampling as usize * sof.max_vertical_sampling as usize,
+            [0; 64],
         );
-        #[allow(clippy::uninit_vec)]
-        unsafe {
-            blocks.set_len(blocks.capacity());
+        let upsample = self.upsampler(sof);
+        let mut i = 0;
+        for v in 0..sof.max_vertical_sampling as usize {
+            for h in 0..sof.max_horizontal_sampling as usize {
+                let y = upsample(0, v, h);
+                for (gray, &y) in out.blocks[i].iter_mut().zip(&y.0) {
+                    *gray = to_u8(y);
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /// Convert to RGB in the JFIF color space.
+    ///
+    /// 4 components are taken as CMYK without the Adobe transform.
+    pub fn to_rgb(&self, sof: &StartOfFrameInfo) -> McuRGB {
+        let mut rgb = McuRGB::default();
+        self.to_rgb_into(sof, ColorSpace::default(), &mut rgb);
+        rgb
+    }
+
+    /// Convert to RGB like [`Self::to_rgb`] in `color_space`, reusing the buffer of `out`.
+    pub fn to_rgb_into(&self, sof: &StartOfFrameInfo, color_space: ColorSpace, out: &mut McuRGB) {
+        if sof.component_number == 4 {
+            *out = self.to_cmyk(sof, ColorTransform::None).to_rgb(false);
+            return;
         }
+        out.width_blocks = sof.max_horizontal_sampling;
+        out.height_blocks = sof.max_vertical_sampling;
+        let len = (sof.max_horizontal_sampling * sof.max_vertical_sampling) as usize;
+        let matrix = color_space.matrix();
+        out.set_gray(sof.component_number == 1);
+        if sof.component_number == 1 {
+            out.gray.resize(len, [0; 64]);
+            for (gray, y) in out.gray.iter_mut().zip(&self.blocks) {
+                for i in 0..64 {
+                    gray[i] = chomp(y.0[i] as i32 * matrix.y + matrix.bias);
+                }
+            }
+            return;
+        }
+        let blocks = &mut out.blocks;
+        blocks.resize(len, [RGB::default(); 64]);
 
-        let size = sof
-            .component_infos
-            .map(|c| c.horizontal_sampling * c.vertical_sampling);
-        assert!(size[1] == 1 && size[2] == 1, "only support 4:4:4 or 4:1:1");
-        let offset = [0, size[0] as usize, (size[0] + size[1]) as usize];
+        let upsample = self.upsampler(sof);
         let mut i = 0;
-        for v in 0..sof.max_vertical_sampling {
-            for h in 0..sof.max_horizontal_sampling {
-                let y = self.blocks[i];
-                let cb = if size[1] == 1 && sof.max_vertical_sampling == 2 {
-                    self.blocks[offset[1]].upsample_2x2(v as usize, h as usize)
-                } else {
-                    self.blocks[offset[1]]
-                };
-                let cr = if size[2] == 1 && sof.max_vertical_sampling == 2 {
-                    self.blocks[offset[2]].upsample_2x2(v as usize, h as usize)
-                } else {
-                    self.blocks[offset[2]]
-                };
+        for v in 0..sof.max_vertical_sampling as usize {
+            for h in 0..sof.max_horizontal_sampling as usize {
+                let y = upsample(0, v, h);
+                let cb = upsample(1, v, h);
+                let cr = upsample(2, v, h);
                 let rgb = &mut blocks[i];
                 for i in 0..64 {
-                    fn chomp(x: i32) -> u8 {
-                        (((x >> 10) as i16).clamp(i8::MIN as _, i8::MAX as _) as i8 as u8) ^ 0x80
-                    }
-                    fn fixed(x: f32) -> i32 {
-                        (x * 1024.0) as i32
-                    }
-                    let y = (y.0[i] as i32) << 10;
-                    let cb = cb.0[i] as i32;
-                    let cr = cr.0[i] as i32;
-                    let r = chomp(y + fixed(1.402) * cr);
-                    let g = chomp(y - fixed(0.344) * cb - fixed(0.714) * cr);
-                    let b = chomp(y + fixed(1.772) * cb);
-                    rgb[i] = RGB { r, g, b };
+                    ycc_to_rgb(y.0[i], cb.0[i], cr.0[i], &matrix, &mut rgb[i]);
                 }
                 i += 1;
             }
         }
-        McuRGB {
+    }
+
+    /// Convert 4 components to CMYK.
+    pub fn to_cmyk(&self, sof: &StartOfFrameInfo, transform: ColorTransform) -> McuCMYK {
+        assert_eq!(sof.component_number, 4);
+        let upsample = self.upsampler(sof);
+        let mut blocks = vec![];
+        for v in 0..sof.max_vertical_sampling as usize {
+            for h in 0..sof.max_horizontal_sampling as usize {
+                let [c, m, y, k] = [0, 1, 2, 3].map(|id| upsample(id, v, h));
+                let mut cmyk = [CMYK::default(); 64];
+                for i in 0..64 {
+                    let k = chomp((k.0[i] as i32) << 10);
+                    cmyk[i] = match transform {
+                        ColorTransform::YCCK => {
+                            // YCbCr of the inverted CMY
+                            let mut rgb = RGB::default();
+                            ycc_to_rgb(c.0[i], m.0[i], y.0[i], &JFIF, &mut rgb);
+                            CMYK {
+                                c: 255 - rgb.r,
+                                m: 255 - rgb.g,
+                                y: 255 - rgb.b,
+                                k,
+                            }
+                        }
+                        _ => CMYK {
+                            c: chomp((c.0[i] as i32) << 10),
+                            m: chomp((m.0[i] as i32) << 10),
+                            y: chomp((y.0[i] as i32) << 10),
+                            k,
+                        },
+                    };
+                }
+                blocks.push(cmyk);
+            }
+        }
+        McuCMYK {
             blocks,
             width_blocks: sof.max_horizontal_sampling,
             height_blocks: sof.max_vertical_sampling,
+            ..Default::default()
+        }
+    }
+
+    /// Return a function to get the block of a component covering the (`v`, `h`)-th block
+    /// at the max sampling factors.
+    fn upsampler<'a>(
+        &'a self,
+        sof: &'a StartOfFrameInfo,
+    ) -> impl Fn(usize, usize, usize) -> Block + 'a {
+        let (max_h, max_v) = (
+            sof.max_horizontal_sampling as usize,
+            sof.max_vertical_sampling as usize,
+        );
+        // the index of the first block of each component
+        let mut offset = [0; 4];
+        for (id, c) in sof.component_infos.iter().enumerate().take(3) {
+            offset[id + 1] = offset[id] + c.blocks_per_mcu();
+        }
+        move |id: usize, v: usize, h: usize| {
+            let c = &sof.component_infos[id];
+            let (ch, cv) = (c.horizontal_sampling as usize, c.vertical_sampling as usize);
+            assert!(
+                max_h % ch == 0 && max_v % cv == 0,
+                "unsupported sampling factor: {ch}x{cv}"
+            );
+            let (fh, fv) = (max_h / ch, max_v / cv);
+            let block = &self.blocks[offset[id] + v / fv * ch + h / fh];
+            match (fh, fv) {
+                (1, 1) => *block,
+                _ => block.upsample(fh, fv, h % fh, v % fv),
+            }
+        }
+    }
+}
+
+/// Convert a level shifted sample to u8.
+#[inline]
+pub(crate) fn to_u8(x: i16) -> u8 {
+    (x.clamp(i8::MIN as _, i8::MAX as _) as i8 as u8) ^ 0x80
+}
+
+/// Convert a level shifted sample in 10-bit fixed point to u8.
+#[inline]
+fn chomp(x: i32) -> u8 {
+    (((x >> 10) as i16).clamp(i8::MIN as _, i8::MAX as _) as i8 as u8) ^ 0x80
+}
+
+/// The filter to upsample the subsampled chroma to the luma.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Upsampling {
+    /// Replicate each chroma sample, which is the fastest but blocky on chroma edges.
+    #[default]
+    NearestNeighbor,
+    /// Interpolate between the centers of the nearest chroma samples, like the "fancy"
+    /// upsampling of libjpeg.
+    ///
+    /// The samples of adjacent MCUs are needed, so the whole image is decoded into planes first,
+    /// which takes more time and memory.
+    Bilinear,
+}
+
+/// Upsample a `width * height` plane by `fh` x `fv` bilinearly, into `out_width * out_height`.
+///
+/// The edge samples are replicated beyond the plane.
+pub(crate) fn upsample_bilinear(
+    plane: &[u8],
+    (width, height): (usize, usize),
+    (fh, fv): (usize, usize),
+    (out_width, out_height): (usize, usize),
+) -> Vec<u8> {
+    // the two nearest samples of each output position and the weight of the second,
+    // in units of 1 / 2f
+    let axis = |f: usize, len: usize, out_len: usize| {
+        (0..out_len)
+            .map(|x| {
+                let pos = (2 * x + 1) as isize - f as isize;
+                let (i, frac) = (
+                    pos.div_euclid(2 * f as isize),
+                    pos.rem_euclid(2 * f as isize),
+                );
+                let clamp = |i: isize| i.clamp(0, len as isize - 1) as usize;
+                (clamp(i), clamp(i + 1), frac as u32)
+            })
+            .collect::<Vec<_>>()
+    };
+    let (xs, ys) = (axis(fh, width, out_width), axis(fv, height, out_height));
+    let (dx, dy) = (2 * fh as u32, 2 * fv as u32);
+    let mut out = Vec::with_capacity(out_width * out_height);
+    for &(y0, y1, wy) in &ys {
+        let (row0, row1) = (&plane[y0 * width..][..width], &plane[y1 * width..][..width]);
+        for &(x0, x1, wx) in &xs {
+            let top = row0[x0] as u32 * (dx - wx) + row0[x1] as u32 * wx;
+            let bottom = row1[x0] as u32 * (dx - wx) + row1[x1] as u32 * wx;
+            let sum = top * (dy - wy) + bottom * wy;
+            out.push(((sum + dx * dy / 2) / (dx * dy)) as u8);
+        }
+    }
+    out
+}
+
+/// The matrix and the range of YCbCr to convert to RGB.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// BT.601 in full range, as in JFIF.
+    #[default]
+    Bt601,
+    /// BT.601 in limited range, with Y in 16..=235 and Cb, Cr in 16..=240.
+    Bt601Limited,
+    /// BT.709 in full range.
+    Bt709,
+    /// BT.709 in limited range.
+    Bt709Limited,
+}
+
+impl ColorSpace {
+    pub(crate) fn matrix(self) -> YccMatrix {
+        match self {
+            ColorSpace::Bt601 => JFIF,
+            ColorSpace::Bt601Limited => YccMatrix::new([1.402, 0.344, 0.714, 1.772], true),
+            ColorSpace::Bt709 => YccMatrix::new([1.5748, 0.1873, 0.4681, 1.8556], false),
+            ColorSpace::Bt709Limited => YccMatrix::new([1.5748, 0.1873, 0.4681, 1.8556], true),
         }
     }
 }
 
+/// The channel order of the interleaved bytes of a pixel, and whether it has an alpha of 255.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    #[default]
+    Rgb,
+    Bgr,
+    Rgba,
+    /// The layout of Windows bitmaps and many GPU textures.
+    Bgra,
+}
+
+impl PixelFormat {
+    /// The number of bytes of a pixel.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+        }
+    }
+}
+
+/// The coefficients of YCbCr to RGB in 10-bit fixed point, for level shifted samples.
+pub(crate) struct YccMatrix {
+    y: i32,
+    bias: i32,
+    cr_r: i32,
+    cb_g: i32,
+    cr_g: i32,
+    cb_b: i32,
+}
+
+/// BT.601 in full range.
+const JFIF: YccMatrix = YccMatrix::new([1.402, 0.344, 0.714, 1.772], false);
+
+impl YccMatrix {
+    /// The coefficients of Cr for R, Cb and Cr for G, and Cb for B in full range.
+    const fn new([cr_r, cb_g, cr_g, cb_b]: [f32; 4], limited: bool) -> Self {
+        const fn fixed(x: f32) -> i32 {
+            (x * 1024.0) as i32
+        }
+        if !limited {
+            return YccMatrix {
+                y: fixed(1.0),
+                // rounded to the nearest
+                bias: fixed(0.5),
+                cr_r: fixed(cr_r),
+                cb_g: fixed(cb_g),
+                cr_g: fixed(cr_g),
+                cb_b: fixed(cb_b),
+            };
+        }
+        let (y, c) = (255.0 / 219.0, 255.0 / 224.0);
+        YccMatrix {
+            y: fixed(y),
+            // Y - 16 from the level shifted Y, rounded to the nearest
+            bias: fixed(y * (128.0 - 16.0) - 128.0 + 0.5),
+            cr_r: fixed(c * cr_r),
+            cb_g: fixed(c * cb_g),
+            cr_g: fixed(c * cr_g),
+            cb_b: fixed(c * cb_b),
+        }
+    }
+}
+
+impl YccMatrix {
+    /// Convert a pixel of YCbCr in u8 to RGB.
+    pub(crate) fn rgb(&self, y: u8, cb: u8, cr: u8) -> RGB {
+        let [y, cb, cr] = [y, cb, cr].map(|x| (x ^ 0x80) as i8 as i16);
+        let mut rgb = RGB::default();
+        ycc_to_rgb(y, cb, cr, self, &mut rgb);
+        rgb
+    }
+
+    /// Convert a gray pixel of Y in u8 to RGB.
+    pub(crate) fn gray(&self, y: u8) -> RGB {
+        let y = chomp(((y ^ 0x80) as i8 as i32) * self.y + self.bias);
+        RGB { r: y, g: y, b: y }
+    }
+}
+
+/// Convert level shifted YCbCr to RGB.
+///
+/// Write to `rgb` rather than return, which is much faster in the loop of a block.
+#[inline(always)]
+fn ycc_to_rgb(y: i16, cb: i16, cr: i16, m: &YccMatrix, rgb: &mut RGB) {
+    // the samples out of range after the IDCT are clamped first, like libjpeg
+    let [y, cb, cr] = [y, cb, cr].map(|x| x.clamp(i8::MIN as _, i8::MAX as _) as i32);
+    let y = y * m.y + m.bias;
+    rgb.r = chomp(y + m.cr_r * cr);
+    rgb.g = chomp(y - m.cb_g * cb - m.cr_g * cr);
+    rgb.b = chomp(y + m.cb_b * cb);
+}
+
+/// The IDCT matrix in 10-bit fixed point, whose transpose is the FDCT matrix:
+/// `cos((2i + 1) * j * PI / 16) * 1024`, scaled by `1 / sqrt(2)` for `j = 0`.
+#[rustfmt::skip]
+pub(crate) const IDCT: [[i16; 8]; 8] = [
+    [724,  1004,  946,   851,  724,   569,  392,   200],
+    [724,   851,  392,  -200, -724, -1004, -946,  -569],
+    [724,   569, -392, -1004, -724,   200,  946,   851],
+    [724,   200, -946,  -569,  724,   851, -392, -1004],
+    [724,  -200, -946,   569,  724,  -851, -392,  1004],
+    [724,  -569, -392,  1004, -724,  -200,  946,  -851],
+    [724,  -851,  392,   200, -724,  1004, -946,   569],
+    [724, -1004,  946,  -851,  724,  -569,  392,  -200],
+];
+
+/// The `n`-point IDCT matrices like [`IDCT`] for n in 1, 2, 4, 8, indexed by log2(n).
+#[rustfmt::skip]
+const IDCT_SCALED: [[[i16; 8]; 8]; 4] = [
+    [
+        [724, 0, 0, 0, 0, 0, 0, 0],
+        [0; 8], [0; 8], [0; 8], [0; 8], [0; 8], [0; 8], [0; 8],
+    ],
+    [
+        [724,  724, 0, 0, 0, 0, 0, 0],
+        [724, -724, 0, 0, 0, 0, 0, 0],
+        [0; 8], [0; 8], [0; 8], [0; 8], [0; 8], [0; 8],
+    ],
+    [
+        [724,  946,  724,  392, 0, 0, 0, 0],
+        [724,  392, -724, -946, 0, 0, 0, 0],
+        [724, -392, -724,  946, 0, 0, 0, 0],
+        [724, -946,  724, -392, 0, 0, 0, 0],
+        [0; 8], [0; 8], [0; 8], [0; 8],
+    ],
+    IDCT,
+];
+
+/// Dequantize a coefficient, saturating at the range of `i16` like [`Block::dequantize`].
+#[inline(always)]
+pub(crate) fn dequantize_one(x: i16, q: u16) -> i32 {
+    (x as i32 * q as i32).clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+/// [`Block::idct`] of the coefficients in natural order given by `coef`.
+#[inline(always)]
+fn idct_with(coef: impl Fn(usize) -> i32) -> Block {
+    let idct = &IDCT;
+    // 1D IDCT
+    let mut res1 = [0i32; 64];
+    for i in 0..8 {
+        for j in 0..8 {
+            // 10bit fixed point
+            let mut v = 0;
+            for x in 0..8 {
+                v += coef(i * 8 + x) * idct[j][x] as i32;
+            }
+            res1[j * 8 + i] = v;
+        }
+    }
+    // 1D IDCT
+    let mut res2 = Block([0; 64]);
+    for j in 0..8 {
+        for i in 0..8 {
+            // 20bit fixed point
+            let mut v = 0;
+            for x in 0..8 {
+                v += res1[j * 8 + x] * idct[i][x] as i32;
+            }
+            // divided by 4 out of the fixed point, rounded to the nearest
+            res2.0[i * 8 + j] = ((v + (1 << 21)) >> 22) as i16;
+        }
+    }
+    res2
+}
+
+/// The index in zigzag order of each coefficient in natural order.
+#[rustfmt::skip]
+pub(crate) const ZIGZAG: [usize; 64] = [
+     0,  1,  5,  6, 14, 15, 27, 28,
+     2,  4,  7, 13, 16, 26, 29, 42,
+     3,  8, 12, 17, 25, 30, 41, 43,
+     9, 11, 18, 24, 31, 40, 44, 53,
+    10, 19, 23, 32, 39, 45, 52, 54,
+    20, 22, 33, 38, 46, 51, 55, 60,
+    21, 34, 37, 47, 50, 56, 59, 61,
+    35, 36, 48, 49, 57, 58, 62, 63,
+];
+
 impl Block {
-    pub fn dequantize(&self, qt: &[i16; 64]) -> Self {
-        let mut block = Block::uninit();
+    /// Multiply by the quantization table, saturating at the range of `i16`.
+    pub fn dequantize(&self, qt: &[u16; 64]) -> Self {
+        let mut block = Block([0; 64]);
         for i in 0..64 {
-            block.0[i] = self.0[i] * qt[i];
+            let v = self.0[i] as i32 * qt[i] as i32;
+            block.0[i] = v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+        block
+    }
+
+    /// [`Self::dequantize`] and then [`Self::zigzag`] in one pass, with SIMD if available.
+    pub fn dequantize_zigzag(&self, qt: &[u16; 64]) -> Self {
+        #[cfg(target_arch = "x86_64")]
+        if crate::simd::x86_feature!("sse2") {
+            // SAFETY: SSE2 is available
+            return unsafe { crate::simd::dequantize_zigzag_sse2(self, qt) };
+        }
+        #[cfg(target_arch = "aarch64")]
+        if crate::simd::aarch64_feature!("neon") {
+            // SAFETY: NEON is available
+            return unsafe { crate::simd::dequantize_zigzag_neon(self, qt) };
+        }
+        self.dequantize_zigzag_scalar(qt)
+    }
+
+    /// The portable version of [`Self::dequantize_zigzag`].
+    pub(crate) fn dequantize_zigzag_scalar(&self, qt: &[u16; 64]) -> Self {
+        let mut block = Block([0; 64]);
+        for i in 0..64 {
+            let z = ZIGZAG[i];
+            let v = self.0[z] as i32 * qt[z] as i32;
+            block.0[i] = v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
         }
         block
     }
 
     pub fn zigzag(&self) -> Self {
-        #[rustfmt::skip]
-        const ZIGZAG: [usize; 64] = [
-             0,  1,  5,  6, 14, 15, 27, 28,
-             2,  4,  7, 13, 16, 26, 29, 42,
-             3,  8, 12, 17, 25, 30, 41, 43,
-             9, 11, 18, 24, 31, 40, 44, 53,
-            10, 19, 23, 32, 39, 45, 52, 54,
-            20, 22, 33, 38, 46, 51, 55, 60,
-            21, 34, 37, 47, 50, 56, 59, 61,
-            35, 36, 48, 49, 57, 58, 62, 63,
-        ];
-
-        let mut x = Block::uninit();
+        let mut x = Block([0; 64]);
         for i in 0..8 {
             for j in 0..8 {
                 x.0[i * 8 + j] = self.0[ZIGZAG[i * 8 + j]];
@@ -128,76 +733,581 @@ impl Block {
         x
     }
 
+    /// The coefficients of a block in zigzag order, such as from entropy decoding,
+    /// in natural order.
+    pub fn in_natural_order(&self) -> [i16; 64] {
+        self.zigzag().0
+    }
+
+    /// Format a block in zigzag order, showing the coefficients in natural order
+    /// rather than in storage order like [`Debug`].
+    pub fn debug_zigzag(&self) -> impl core::fmt::Debug + '_ {
+        struct Zigzag<'a>(&'a Block);
+        impl core::fmt::Debug for Zigzag<'_> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                writeln!(f, "zigzag order, in natural order:")?;
+                write!(f, "{:?}", Block(self.0.in_natural_order()))
+            }
+        }
+        Zigzag(self)
+    }
+
+    /// Reorder the coefficients from natural order to zigzag order, the inverse of [`Self::zigzag`].
+    pub fn dezigzag(&self) -> Self {
+        let mut x = Block([0; 64]);
+        for i in 0..64 {
+            x.0[ZIGZAG[i]] = self.0[i];
+        }
+        x
+    }
+
     pub fn idct(&self) -> Self {
-        lazy_static::lazy_static! {
-            // 10bit fixed point
-            static ref IDCT: [[i16; 8]; 8] = {
-                use std::f32::consts::PI;
-                let mut m = [[0.0; 8]; 8];
-                for i in 0..8 {
-                    for j in 0..8 {
-                        m[i][j] = ((2 * i + 1) as f32 * j as f32 * PI / 16.0).cos();
-                    }
-                    m[i][0] *= 1.0 / 2_f32.sqrt();
-                }
-                m.map(|m| m.map(|f| (f * 1024.0).round() as i16))
-            };
+        #[cfg(target_arch = "x86_64")]
+        if crate::simd::x86_feature!("avx2") {
+            // SAFETY: AVX2 is available
+            return unsafe { crate::simd::idct_avx2(self) };
+        }
+        self.idct_scalar()
+    }
+
+    /// The portable version of [`Self::idct`].
+    pub(crate) fn idct_scalar(&self) -> Self {
+        idct_with(|k| self.0[k] as i32)
+    }
+
+    /// Dequantize, [`Self::zigzag`] and [`Self::idct`] a block in zigzag order in one pass,
+    /// with `qt` in natural order from [`QuantizationTable::in_natural_order`].
+    ///
+    /// Each coefficient is multiplied as it is loaded by the first 1D IDCT,
+    /// so there is no separate pass of dequantization.
+    pub fn idct_dequant(&self, qt: &[u16; 64]) -> Self {
+        #[cfg(target_arch = "x86_64")]
+        if crate::simd::x86_feature!("avx2") {
+            // SAFETY: AVX2 is available
+            return unsafe { crate::simd::idct_dequant_avx2(self, qt) };
         }
+        self.idct_dequant_scalar(qt)
+    }
+
+    /// The portable version of [`Self::idct_dequant`].
+    pub(crate) fn idct_dequant_scalar(&self, qt: &[u16; 64]) -> Self {
+        idct_with(|k| dequantize_one(self.0[ZIGZAG[k]], qt[k]))
+    }
+
+    /// The mean of the samples from the DC alone, dequantized by `q`, like [`Self::idct_scaled`]
+    /// of 1 without the multiplications.
+    pub(crate) fn dc_mean(&self, q: u16) -> i16 {
+        let dc = self.0[0] as i32 * q as i32;
+        ((dc + 4) >> 3).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
 
-        let idct = &*IDCT;
+    /// IDCT of the top-left `n`x`n` coefficients for n in 1, 2, 4, 8,
+    /// into `n`x`n` samples scaled down by `8 / n` in the top-left of the block.
+    pub fn idct_scaled(&self, n: usize) -> Self {
+        assert!(matches!(n, 1 | 2 | 4 | 8), "invalid IDCT size: {n}");
+        let idct = &IDCT_SCALED[n.trailing_zeros() as usize];
         // 1D IDCT
-        #[allow(invalid_value)]
-        #[allow(clippy::uninit_assumed_init)]
-        let mut res1: [i32; 64] = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-        for i in 0..8 {
-            for j in 0..8 {
+        let mut res1 = [0i32; 64];
+        for i in 0..n {
+            for j in 0..n {
                 // 10bit fixed point
                 let mut v = 0;
-                for x in 0..8 {
+                for x in 0..n {
                     v += self.0[i * 8 + x] as i32 * idct[j][x] as i32;
                 }
                 res1[j * 8 + i] = v;
             }
         }
         // 1D IDCT
-        let mut res2 = Block::uninit();
+        let mut res2 = Block([0; 64]);
+        for j in 0..n {
+            for i in 0..n {
+                // 20bit fixed point
+                let mut v = 0;
+                for x in 0..n {
+                    v += res1[j * 8 + x] * idct[i][x] as i32;
+                }
+                // divided by 4 out of the fixed point, rounded to the nearest
+                res2.0[i * 8 + j] = ((v + (1 << 21)) >> 22) as i16;
+            }
+        }
+        res2
+    }
+
+    /// Forward DCT, the inverse of [`Self::idct`], from samples in -128..128.
+    pub fn fdct(&self) -> Self {
+        let idct = &IDCT;
+        // 1D DCT
+        let mut res1 = [0i32; 64];
+        for i in 0..8 {
+            for j in 0..8 {
+                let mut v = 0;
+                for x in 0..8 {
+                    v += self.0[i * 8 + x] as i32 * idct[x][j] as i32;
+                }
+                // 3bit fixed point
+                res1[j * 8 + i] = v >> 7;
+            }
+        }
+        // 1D DCT
+        let mut res2 = Block([0; 64]);
         for j in 0..8 {
             for i in 0..8 {
-                // 20bit fixed point
+                // 13bit fixed point
                 let mut v = 0;
                 for x in 0..8 {
-                    v += res1[j * 8 + x] * idct[i][x] as i32;
+                    v += res1[j * 8 + x] * idct[x][i] as i32;
                 }
-                res2.0[i * 8 + j] = ((v / 4) >> 20) as i16;
+                // v / 4, rounded
+                res2.0[i * 8 + j] = ((v + (1 << 14)) >> 15) as i16;
             }
         }
         res2
     }
 
     pub fn upsample_2x2(&self, oh: usize, ow: usize) -> Self {
-        match (oh, ow) {
-            (0, 0) => self.upsample_2x2_inline::<0, 0>(),
-            (0, 1) => self.upsample_2x2_inline::<0, 1>(),
-            (1, 0) => self.upsample_2x2_inline::<1, 0>(),
-            (1, 1) => self.upsample_2x2_inline::<1, 1>(),
-            _ => unreachable!(),
+        self.upsample(2, 2, ow, oh)
+    }
+
+    /// Scale the block by `factor_h`x`factor_v` with nearest neighbor,
+    /// and take the (`sub_x`, `sub_y`)-th 8x8 part of it.
+    pub fn upsample(&self, factor_h: usize, factor_v: usize, sub_x: usize, sub_y: usize) -> Self {
+        debug_assert!(sub_x < factor_h && sub_y < factor_v);
+        match (factor_h, factor_v, sub_x, sub_y) {
+            // fast path for 4:2:0
+            (2, 2, 0, 0) => self.upsample_inline::<2, 2, 0, 0>(),
+            (2, 2, 1, 0) => self.upsample_inline::<2, 2, 1, 0>(),
+            (2, 2, 0, 1) => self.upsample_inline::<2, 2, 0, 1>(),
+            (2, 2, 1, 1) => self.upsample_inline::<2, 2, 1, 1>(),
+            _ => {
+                let mut x = Block([0; 64]);
+                for i in 0..8 {
+                    let row = (sub_y * 8 + i) / factor_v * 8;
+                    for j in 0..8 {
+                        x.0[i * 8 + j] = self.0[row + (sub_x * 8 + j) / factor_h];
+                    }
+                }
+                x
+            }
         }
     }
 
-    fn upsample_2x2_inline<const I: usize, const J: usize>(&self) -> Self {
-        let mut x = Block::uninit();
+    #[inline]
+    fn upsample_inline<const FH: usize, const FV: usize, const SX: usize, const SY: usize>(
+        &self,
+    ) -> Self {
+        let mut x = Block([0; 64]);
         for i in 0..8 {
             for j in 0..8 {
-                x.0[i * 8 + j] = self.0[(I * 8 + i) / 2 * 8 + (J * 8 + j) / 2];
+                x.0[i * 8 + j] = self.0[(SY * 8 + i) / FV * 8 + (SX * 8 + j) / FH];
             }
         }
         x
     }
+}
 
-    #[allow(invalid_value)]
-    #[allow(clippy::uninit_assumed_init)]
-    #[inline]
-    fn uninit() -> Self {
-        unsafe { std::mem::MaybeUninit::uninit().assume_init() }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::start_of_frame_0::ComponentInfo;
+    use crate::Decoder;
+
+    /// A xorshift generator of random numbers from `seed`.
+    fn xorshift(mut seed: u32) -> impl FnMut() -> u32 {
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        }
+    }
+
+    /// Decode a file into a row-major image.
+    fn decode(path: &str) -> (usize, usize, Vec<RGB>) {
+        let file = std::fs::File::open(path).expect("failed to read file");
+        let reader = Decoder::new(file).read().unwrap();
+        let (width, height, pixels) = reader.decode_to_rgb8().unwrap();
+        (width as usize, height as usize, pixels)
+    }
+
+    /// Read a binary PPM file.
+    fn read_ppm(path: &str) -> (usize, usize, Vec<RGB>) {
+        let data = std::fs::read(path).expect("failed to read file");
+        let mut fields = data.splitn(5, |b| b.is_ascii_whitespace());
+        assert_eq!(fields.next(), Some(&b"P6"[..]));
+        let mut next = || -> usize {
+            let field = fields.next().unwrap();
+            std::str::from_utf8(field).unwrap().parse().unwrap()
+        };
+        let (width, height) = (next(), next());
+        assert_eq!(next(), 255);
+        let pixels = fields.next().unwrap();
+        let pixels = pixels.chunks(3).map(|p| RGB {
+            r: p[0],
+            g: p[1],
+            b: p[2],
+        });
+        (width, height, pixels.collect())
+    }
+
+    #[test]
+    fn test_idct() {
+        let mut rand = xorshift(0x2545F491);
+        for _ in 0..1000 {
+            // |64 * 31 * 1024 * 1024| fits in i32
+            let block = Block([0; 64].map(|_| (rand() % 63) as i16 - 31));
+            assert_eq!(block.idct(), block.idct_scalar(), "{block:?}");
+        }
+    }
+
+    #[test]
+    fn test_dequantize_zigzag() {
+        let mut rand = xorshift(0x2545F491);
+        for _ in 0..1000 {
+            let block = Block([0; 64].map(|_| rand() as i16));
+            // including 16-bit tables with the top bit set, and saturation
+            let qt = [0; 64].map(|_| match rand() % 3 {
+                0 => rand() as u16,
+                _ => rand() as u16 % 256,
+            });
+            let expected = block.dequantize(&qt).zigzag();
+            assert_eq!(block.dequantize_zigzag(&qt), expected, "{block:?}");
+            assert_eq!(block.dequantize_zigzag_scalar(&qt), expected, "{block:?}");
+        }
+        let block = Block([i16::MIN; 64]);
+        assert_eq!(
+            block.dequantize_zigzag(&[u16::MAX; 64]),
+            Block([i16::MIN; 64])
+        );
+    }
+
+    #[test]
+    fn test_idct_dequant() {
+        let mut rand = xorshift(0x2545F491);
+        for _ in 0..1000 {
+            // the dequantized coefficients in -31..=31 like `test_idct`
+            let block = Block([0; 64].map(|_| (rand() % 11) as i16 - 5));
+            let qt = QuantizationTable {
+                id: 0,
+                values: [0; 64].map(|_| (rand() % 7) as u16),
+            };
+            let expected = block.dequantize(&qt.values).zigzag().idct();
+            let natural = qt.in_natural_order();
+            assert_eq!(block.idct_dequant(&natural), expected, "{block:?}");
+            assert_eq!(block.idct_dequant_scalar(&natural), expected, "{block:?}");
+        }
+    }
+
+    /// The portable block transforms over random blocks, also for `cargo miri test`.
+    #[test]
+    fn test_block_scalar() {
+        let mut rand = xorshift(0x2545F491);
+        for _ in 0..if cfg!(miri) { 4 } else { 100 } {
+            let block = Block([0; 64].map(|_| (rand() % 63) as i16 - 31));
+            let qt = [0; 64].map(|_| (rand() % 4) as u16);
+            let x = block.dequantize(&qt).zigzag();
+            assert_eq!(block.dequantize_zigzag_scalar(&qt), x);
+            assert_eq!(x.dezigzag(), block.dequantize(&qt));
+            let x = x.idct_scalar();
+            assert!(x.0.iter().all(|v| (-1024..1024).contains(v)), "{x:?}");
+            assert_eq!(x.upsample(2, 2, 1, 1), x.upsample_2x2(1, 1));
+        }
+    }
+
+    #[test]
+    fn test_idct_dc() {
+        // a DC-only block is uniform at DC / 8, rounded to the nearest
+        for dc in -1024..1024 {
+            let mut block = Block([0; 64]);
+            block.0[0] = dc;
+            let x = block.idct();
+            assert_eq!(x, Block([x.0[0]; 64]), "DC {dc}");
+            assert!((x.0[0] * 8 - dc).abs() <= 4, "DC {dc}: {}", x.0[0]);
+            assert_eq!(block.idct_scalar(), x);
+        }
+    }
+
+    #[test]
+    fn test_idct_tables() {
+        use std::f32::consts::PI;
+        for k in 0..4 {
+            let n = 1 << k;
+            let mut m = [[0; 8]; 8];
+            for i in 0..n {
+                for j in 0..n {
+                    let mut f = ((2 * i + 1) as f32 * j as f32 * PI / (2 * n) as f32).cos();
+                    if j == 0 {
+                        f *= 1.0 / 2_f32.sqrt();
+                    }
+                    m[i][j] = (f * 1024.0).round() as i16;
+                }
+            }
+            assert_eq!(IDCT_SCALED[k], m, "{n}-point");
+        }
+        assert_eq!(IDCT_SCALED[3], IDCT);
+    }
+
+    #[test]
+    fn test_idct_scaled() {
+        let block = Block(std::array::from_fn(|i| [120, -24, 12, 0][i % 4] >> (i / 8)));
+        assert_eq!(block.idct_scaled(8), block.idct_scalar());
+        // DC only, 120 / 8
+        assert_eq!(block.idct_scaled(1).0[0], 15);
+        // the average of each 4x4 quarter
+        let full = block.idct_scalar();
+        let half = block.idct_scaled(2);
+        for (y, x) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let sum: i32 = (0..16)
+                .map(|i| full.0[(y * 4 + i / 4) * 8 + x * 4 + i % 4] as i32)
+                .sum();
+            assert!(
+                (sum / 16).abs_diff(half.0[y * 8 + x] as i32) <= 8,
+                "{full:?} {half:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_zigzag() {
+        let block = Block(std::array::from_fn(|i| i as i16));
+        assert_eq!(block.zigzag().dezigzag(), block);
+        assert_eq!(block.dezigzag().zigzag(), block);
+        // the 3rd in zigzag order is the 2nd row
+        assert_eq!(block.zigzag().0[8], 2);
+        assert_eq!(block.dezigzag().0[2], 8);
+
+        assert_eq!(block.in_natural_order(), block.zigzag().0);
+        let debug = format!("{:?}", block.debug_zigzag());
+        assert!(
+            debug.starts_with("zigzag order, in natural order:\n 0 1 5 6 14"),
+            "{debug}"
+        );
+    }
+
+    #[test]
+    fn test_fdct() {
+        let mut rand = xorshift(0x9E3779B9);
+        for _ in 0..1000 {
+            let block = Block([0; 64].map(|_| (rand() % 256) as i16 - 128));
+            let x = block.fdct().idct();
+            let diff = (0..64).map(|i| (x.0[i] - block.0[i]).abs()).max().unwrap();
+            // both round in fixed point
+            assert!(diff <= 2, "{diff}\n{block:?}");
+        }
+        // DC only
+        assert_eq!(Block([100; 64]).fdct().0[0], 800);
+    }
+
+    #[test]
+    fn test_cropped_line() {
+        let mcu = McuPixels {
+            blocks: vec![std::array::from_fn::<_, 64, _>(|i| i as u8); 2],
+            width_blocks: 2,
+            height_blocks: 1,
+            ..Default::default()
+        };
+        let len = |width, x0| mcu.cropped_line(1, width, x0).count();
+        assert_eq!(len(1920, 1904), 16);
+        assert_eq!(len(1919, 1904), 15);
+        assert_eq!(len(1907, 1904), 3);
+        assert!(mcu.cropped_line(1, 3, 0).eq([8, 9, 10]));
+        assert!(mcu.cropped_line(1, 11, 0).skip(8).eq([8, 9, 10]));
+    }
+
+    #[test]
+    fn test_upsample() {
+        let block = Block(std::array::from_fn(|i| i as i16));
+        for (fh, fv) in [(2, 2), (2, 1), (1, 2), (4, 1), (3, 3)] {
+            for (sx, sy) in (0..fh).flat_map(|x| (0..fv).map(move |y| (x, y))) {
+                let x = block.upsample(fh, fv, sx, sy);
+                for i in 0..8 {
+                    for j in 0..8 {
+                        let (row, col) = ((sy * 8 + i) / fv, (sx * 8 + j) / fh);
+                        assert_eq!(x.0[i * 8 + j], (row * 8 + col) as i16);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_color_space() {
+        let sof = StartOfFrameInfo {
+            precision: 8,
+            height: 8,
+            width: 8,
+            component_number: 3,
+            component_infos: (1..=3)
+                .map(|id| ComponentInfo {
+                    id,
+                    horizontal_sampling: 1,
+                    vertical_sampling: 1,
+                    quant_table_id: 0,
+                })
+                .collect(),
+            max_horizontal_sampling: 1,
+            max_vertical_sampling: 1,
+        };
+        // a gray ramp of Y in 16..=235
+        let ramp: [i16; 64] = std::array::from_fn(|i| (16 + i * 219 / 63) as i16 - 128);
+        let mcu = Mcu {
+            blocks: smallvec::smallvec![Block(ramp), Block([0; 64]), Block([0; 64])],
+        };
+        let gray = |color_space| {
+            let mut rgb = McuRGB::default();
+            mcu.to_rgb_into(&sof, color_space, &mut rgb);
+            assert!(rgb.blocks[0].iter().all(|p| p.r == p.g && p.g == p.b));
+            rgb.blocks[0].map(|p| p.r)
+        };
+        assert_eq!(gray(ColorSpace::Bt601), ramp.map(|y| (y + 128) as u8));
+        for color_space in [ColorSpace::Bt601Limited, ColorSpace::Bt709Limited] {
+            let limited = gray(color_space);
+            assert_eq!((limited[0], limited[63]), (0, 255));
+            assert!(limited.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_subsampling() {
+        for mode in ["420", "422", "440", "411"] {
+            let (width, height, pixels) = decode(&format!("data/pattern_{mode}.jpg"));
+            let (ref_width, ref_height, ref_pixels) = read_ppm(&format!("data/pattern_{mode}.ppm"));
+            assert_eq!((width, height), (ref_width, ref_height));
+            let diffs: Vec<u8> = (pixels.iter().zip(&ref_pixels))
+                .flat_map(|(a, b)| [a.r.abs_diff(b.r), a.g.abs_diff(b.g), a.b.abs_diff(b.b)])
+                .collect();
+            let max_diff = *diffs.iter().max().unwrap();
+            assert!(max_diff <= 8, "4:{mode}: max diff {max_diff}");
+            // about 1.4 with the IDCT truncated
+            let mean_diff = diffs.iter().map(|&d| d as f64).sum::<f64>() / diffs.len() as f64;
+            assert!(mean_diff < 0.75, "4:{mode}: mean diff {mean_diff}");
+        }
+    }
+
+    #[test]
+    fn test_upsample_bilinear() {
+        // a horizontal ramp is interpolated between the centers of the samples
+        let plane = [0, 40, 80, 120];
+        let out = upsample_bilinear(&plane, (4, 1), (2, 1), (8, 1));
+        assert_eq!(out, [0, 10, 30, 50, 70, 90, 110, 120]);
+        let out = upsample_bilinear(&plane, (2, 2), (1, 2), (2, 4));
+        assert_eq!(out, [0, 40, 20, 60, 60, 100, 80, 120]);
+
+        let diff = |a: &[RGB], b: &[RGB]| {
+            (a.iter().zip(b))
+                .flat_map(|(a, b)| [a.r.abs_diff(b.r), a.g.abs_diff(b.g), a.b.abs_diff(b.b)])
+                .map(|d| d as u64)
+                .sum::<u64>()
+        };
+        // decoded by libjpeg with fancy upsampling
+        let (_, _, reference) = read_ppm("data/pattern_420_fancy.ppm");
+        let file = std::fs::File::open("data/pattern_420.jpg").expect("failed to read file");
+        let reader = crate::Decoder::new(file).read().unwrap();
+        let (_, _, pixels) = reader
+            .with_upsampling(Upsampling::Bilinear)
+            .decode_to_rgb8()
+            .unwrap();
+        let (_, _, nearest) = decode("data/pattern_420.jpg");
+        let max_diff = (pixels.iter().zip(&reference))
+            .flat_map(|(a, b)| [a.r.abs_diff(b.r), a.g.abs_diff(b.g), a.b.abs_diff(b.b)])
+            .max()
+            .unwrap();
+        assert!(max_diff <= 8, "max diff {max_diff}");
+        assert!(diff(&pixels, &reference) < diff(&nearest, &reference) / 2);
+    }
+
+    #[test]
+    fn test_cmyk() {
+        for name in ["cmyk", "ycck"] {
+            let data = std::fs::read(format!("data/{name}.jpg")).expect("failed to read file");
+            let reader = Decoder::new(&data[..]).read().unwrap();
+            let (width, height, pixels) = reader.decode_to_cmyk8().unwrap();
+            // decoded by libjpeg without fancy upsampling
+            let reference = std::fs::read(format!("data/{name}.pam")).expect("failed to read file");
+            let header = format!(
+                "P7\nWIDTH {width}\nHEIGHT {height}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE CMYK\nENDHDR\n"
+            );
+            assert!(reference.starts_with(header.as_bytes()));
+            let max_diff = (pixels.iter().flat_map(|p| [p.c, p.m, p.y, p.k]))
+                .zip(&reference[header.len()..])
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+            assert!(max_diff <= 8, "{name}: max diff {max_diff}");
+
+            // written by libjpeg with an Adobe header, so taken as inverted
+            let reader = Decoder::new(&data[..]).read().unwrap();
+            let (_, _, rgb) = reader.decode_to_rgb8().unwrap();
+            for (p, rgb) in pixels.iter().zip(&rgb) {
+                let mul = |x: u8| ((x as u16 * p.k as u16 + 127) / 255) as u8;
+                assert_eq!(
+                    *rgb,
+                    RGB {
+                        r: mul(p.c),
+                        g: mul(p.m),
+                        b: mul(p.y)
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rgb_as_bytes() {
+        let pixels = [RGB { r: 1, g: 2, b: 3 }, RGB { r: 4, g: 5, b: 6 }];
+        assert_eq!(RGB::as_bytes(&pixels), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(RGB::as_bytes(&[]), [0u8; 0]);
+    }
+
+    #[test]
+    fn test_mcu_pixel() {
+        let file = std::fs::File::open("data/pattern_420.jpg").expect("failed to read file");
+        let mcu = crate::Decoder::new(file)
+            .read()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(mcu.dimensions(), (16, 16));
+        for y in 0..16 {
+            let line: Vec<RGB> = mcu.line(y).collect();
+            for (x, p) in line.iter().enumerate() {
+                assert_eq!(mcu.pixel(x, y), *p);
+                assert_eq!(mcu.get_pixel(x, y), Some(*p));
+            }
+        }
+        assert_eq!(mcu.get_pixel(16, 0), None);
+        assert_eq!(mcu.get_pixel(0, 16), None);
+    }
+
+    #[test]
+    fn test_gray_mcu() {
+        let file = std::fs::File::open("data/gray.jpg").expect("failed to read file");
+        let mut reader = crate::Decoder::new(file).read().unwrap();
+        let mcu = reader.next().unwrap().unwrap();
+        let (width, height) = mcu.dimensions();
+        assert!(mcu.is_gray());
+        assert_eq!(mcu.buffer_size(), width * height);
+        let pixels: Vec<RGB> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| mcu.pixel(x, y))
+            .collect();
+        assert!(pixels.iter().all(|p| p.r == p.g && p.g == p.b));
+        assert_eq!(mcu.buffer_size(), width * height);
+
+        // expanded on the fly by `line` too
+        let lines: Vec<RGB> = (0..height).flat_map(|y| mcu.line(y)).collect();
+        assert_eq!(lines, pixels);
+        assert_eq!(mcu.buffer_size(), width * height);
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<McuRGB>();
+        let expanded = McuRGB {
+            blocks: mcu.gray.iter().map(|b| b.map(RGB::from)).collect(),
+            width_blocks: mcu.width_blocks,
+            height_blocks: mcu.height_blocks,
+            ..Default::default()
+        };
+        assert!(!expanded.is_gray());
+        assert_eq!(expanded, mcu);
     }
 }
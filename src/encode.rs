@@ -0,0 +1,288 @@
+//! Baseline JPEG encoding.
+
+use crate::{
+    decode::RGB,
+    mcu::Block,
+    tables::{
+        scale_quantization_table, HuffmanSpec, STD_AC_CHROMA, STD_AC_LUMA, STD_CHROMA_QUANT,
+        STD_DC_CHROMA, STD_DC_LUMA, STD_LUMA_QUANT,
+    },
+};
+use std::io::{BufWriter, Result, Write};
+
+/// Write baseline JPEG in YCbCr 4:4:4, with the standard huffman tables.
+pub struct Encoder<W: Write> {
+    writer: BufWriter<W>,
+    /// 1 to 100.
+    quality: u8,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Scale the standard quantization tables by `quality` in 1..=100, like libjpeg.
+    pub fn new(writer: W, quality: u8) -> Self {
+        Encoder {
+            writer: BufWriter::new(writer),
+            quality: quality.clamp(1, 100),
+        }
+    }
+
+    /// Encode the `width * height` pixels in row-major order.
+    pub fn encode(mut self, width: u16, height: u16, pixels: &[RGB]) -> Result<W> {
+        assert!(width != 0 && height != 0, "empty image");
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        let qts = [
+            scale_quantization_table(&STD_LUMA_QUANT, self.quality),
+            scale_quantization_table(&STD_CHROMA_QUANT, self.quality),
+        ];
+        self.write_headers(width, height, &qts)?;
+
+        let tables = [
+            (
+                HuffmanCodes::new(&STD_DC_LUMA),
+                HuffmanCodes::new(&STD_AC_LUMA),
+            ),
+            (
+                HuffmanCodes::new(&STD_DC_CHROMA),
+                HuffmanCodes::new(&STD_AC_CHROMA),
+            ),
+        ];
+        let (width, height) = (width as usize, height as usize);
+        let mut writer = BitWriter::new(&mut self.writer);
+        let mut last_dc = [0; 3];
+        for y0 in (0..height).step_by(8) {
+            for x0 in (0..width).step_by(8) {
+                // replicate the edge pixels into the padding
+                let mut blocks = [Block([0; 64]); 3];
+                for i in 0..64 {
+                    let (x, y) = ((x0 + i % 8).min(width - 1), (y0 + i / 8).min(height - 1));
+                    let [y, cb, cr] = rgb_to_ycc(pixels[y * width + x]);
+                    blocks[0].0[i] = y;
+                    blocks[1].0[i] = cb;
+                    blocks[2].0[i] = cr;
+                }
+                for (id, block) in blocks.iter().enumerate() {
+                    let table = id.min(1);
+                    let block = quantize(&block.fdct().dezigzag(), &qts[table]);
+                    writer.write_block(&block, &mut last_dc[id], &tables[table])?;
+                }
+            }
+        }
+        writer.flush()?;
+
+        // EOI
+        self.writer.write_all(&[0xFF, 0xD9])?;
+        self.writer.flush()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+
+    /// Write SOI, APP0, DQT, SOF0, DHT and SOS.
+    fn write_headers(&mut self, width: u16, height: u16, qts: &[[u8; 64]; 2]) -> Result<()> {
+        let w = &mut self.writer;
+        // SOI
+        w.write_all(&[0xFF, 0xD8])?;
+        // JFIF 1.1, no units, density 1x1, no thumbnail
+        w.write_all(&[0xFF, 0xE0, 0, 16])?;
+        w.write_all(b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00")?;
+
+        w.write_all(&[0xFF, 0xDB, 0, 2 + 2 * 65])?;
+        for (id, qt) in qts.iter().enumerate() {
+            w.write_all(&[id as u8])?;
+            w.write_all(qt)?;
+        }
+
+        w.write_all(&[0xFF, 0xC0, 0, 17, 8])?;
+        w.write_all(&height.to_be_bytes())?;
+        w.write_all(&width.to_be_bytes())?;
+        // Y, Cb, Cr: 1x1 sampling, with table 0, 1, 1
+        w.write_all(&[3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1])?;
+
+        let specs = [
+            (0x00, &STD_DC_LUMA),
+            (0x10, &STD_AC_LUMA),
+            (0x01, &STD_DC_CHROMA),
+            (0x11, &STD_AC_CHROMA),
+        ];
+        let len: usize = 2 + specs.iter().map(|(_, s)| 17 + s.1.len()).sum::<usize>();
+        w.write_all(&[0xFF, 0xC4])?;
+        w.write_all(&(len as u16).to_be_bytes())?;
+        for (class, (counts, values)) in specs {
+            w.write_all(&[class])?;
+            w.write_all(counts)?;
+            w.write_all(values)?;
+        }
+
+        // Y with DC0/AC0, Cb and Cr with DC1/AC1, the whole spectrum
+        w.write_all(&[0xFF, 0xDA, 0, 12, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0])
+    }
+}
+
+/// Convert to level-shifted YCbCr.
+fn rgb_to_ycc(p: RGB) -> [i16; 3] {
+    let (r, g, b) = (p.r as i32, p.g as i32, p.b as i32);
+    // 16bit fixed point
+    let y = (19595 * r + 38470 * g + 7471 * b + 32768) >> 16;
+    let cb = (-11059 * r - 21709 * g + 32768 * b + 32768) >> 16;
+    let cr = (32768 * r - 27439 * g - 5329 * b + 32768) >> 16;
+    [(y - 128) as i16, cb as i16, cr as i16]
+}
+
+/// Divide by the quantization table, rounded to the nearest.
+fn quantize(block: &Block, qt: &[u8; 64]) -> Block {
+    let mut x = Block([0; 64]);
+    for i in 0..64 {
+        let (v, q) = (block.0[i] as i32, qt[i] as i32);
+        let v = if v < 0 {
+            -((q / 2 - v) / q)
+        } else {
+            (v + q / 2) / q
+        };
+        // the largest magnitude in the standard huffman tables
+        x.0[i] = v.clamp(-1023, 1023) as i16;
+    }
+    x
+}
+
+/// The code and its length of each value.
+struct HuffmanCodes([(u16, u8); 256]);
+
+impl HuffmanCodes {
+    /// Assign the canonical codes by the number of codes of each length.
+    fn new((counts, values): &HuffmanSpec) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut values = values.iter();
+        let mut code = 0;
+        for (len, &count) in counts.iter().enumerate() {
+            for _ in 0..count {
+                codes[*values.next().unwrap() as usize] = (code, len as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanCodes(codes)
+    }
+}
+
+/// Write bits into the entropy-coded segment, with 0xFF stuffed.
+struct BitWriter<W: Write> {
+    writer: W,
+    buf: u32,
+    /// The lower `count` bits of `buf` is pending.
+    count: u8,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(writer: W) -> Self {
+        BitWriter {
+            writer,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    /// Write the lower `len` bits of `bits`.
+    fn write_bits(&mut self, bits: u16, len: u8) -> Result<()> {
+        self.buf = (self.buf << len) | (bits as u32 & ((1 << len) - 1));
+        self.count += len;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.buf >> self.count) as u8;
+            if byte == 0xFF {
+                self.writer.write_all(&[0xFF, 0x00])?;
+            } else {
+                self.writer.write_all(&[byte])?;
+            }
+        }
+        self.buf &= (1 << self.count) - 1;
+        Ok(())
+    }
+
+    /// Write a value after the code of `symbol` with its category in the low nibble.
+    ///
+    /// For AC, the high nibble of `symbol` is the run of zeros before the value.
+    fn write_value(&mut self, codes: &HuffmanCodes, symbol: u8, value: i16) -> Result<()> {
+        let len = 16 - value.unsigned_abs().leading_zeros() as u8;
+        let (code, code_len) = codes.0[(symbol | len) as usize];
+        self.write_bits(code, code_len)?;
+        // negative values are written in one's complement
+        let bits = if value < 0 { value - 1 } else { value };
+        self.write_bits(bits as u16, len)
+    }
+
+    /// Write a block in zigzag order, updating the DC prediction `last_dc`.
+    fn write_block(
+        &mut self,
+        block: &Block,
+        last_dc: &mut i16,
+        (dc, ac): &(HuffmanCodes, HuffmanCodes),
+    ) -> Result<()> {
+        self.write_value(dc, 0, block.0[0] - *last_dc)?;
+        *last_dc = block.0[0];
+
+        let mut zeros = 0;
+        for &x in &block.0[1..] {
+            if x == 0 {
+                zeros += 1;
+                continue;
+            }
+            while zeros >= 16 {
+                // ZRL
+                let (code, len) = ac.0[0xF0];
+                self.write_bits(code, len)?;
+                zeros -= 16;
+            }
+            self.write_value(ac, zeros << 4, x)?;
+            zeros = 0;
+        }
+        if zeros != 0 {
+            // EOB
+            let (code, len) = ac.0[0x00];
+            self.write_bits(code, len)?;
+        }
+        Ok(())
+    }
+
+    /// Pad the last byte with 1s.
+    fn flush(&mut self) -> Result<()> {
+        if self.count != 0 {
+            let len = 8 - self.count;
+            self.write_bits(0xFF, len)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+
+    /// PSNR in dB.
+    fn psnr(a: &[RGB], b: &[RGB]) -> f64 {
+        let se: u64 = (a.iter().zip(b))
+            .flat_map(|(a, b)| [(a.r, b.r), (a.g, b.g), (a.b, b.b)])
+            .map(|(a, b)| (a as i64 - b as i64).pow(2) as u64)
+            .sum();
+        let mse = se as f64 / (a.len() * 3) as f64;
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+
+    #[test]
+    fn test_encode() {
+        let file = std::fs::File::open("data/city.jpg").expect("failed to read file");
+        let (width, height, pixels) = Decoder::new(file).read().unwrap().decode_to_rgb8().unwrap();
+
+        let mut last = 0.0;
+        for quality in [50, 90, 100] {
+            let encoder = Encoder::new(vec![], quality);
+            let jpeg = encoder
+                .encode(width as u16, height as u16, &pixels)
+                .unwrap();
+            let reader = Decoder::new(&jpeg[..]).read().unwrap();
+            let (w, h, decoded) = reader.decode_to_rgb8().unwrap();
+            assert_eq!((w, h), (width, height));
+            let psnr = psnr(&pixels, &decoded);
+            assert!(psnr > 30.0 && psnr > last, "quality {quality}: {psnr}dB");
+            last = psnr;
+        }
+    }
+}
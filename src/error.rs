@@ -0,0 +1,167 @@
+use crate::{huffman::HuffmanTableClass, prelude::*};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+pub type Result<T, E = JpegError> = core::result::Result<T, E>;
+
+/// Errors of decoding a JPEG.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum JpegError {
+    /// An I/O error of the underlying reader.
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The stream ended unexpectedly.
+    Truncated,
+    /// A marker which is not recognized.
+    UnsupportedMarker(u8),
+    /// EOI before any scan.
+    UnexpectedEoi,
+    /// No SOF before the first scan.
+    MissingSof,
+    /// A coding process other than baseline and progressive DCT with huffman coding,
+    /// with the `n` of SOFn.
+    UnsupportedEncoding(u8),
+    /// A sample precision other than 8 bits, e.g. 12-bit images.
+    UnsupportedPrecision(u8),
+    /// A quantization table referred to but not defined.
+    InvalidQuantTableId(u8),
+    /// A scan refers to a huffman table which is not defined.
+    MissingHuffmanTable(HuffmanTableClass),
+    InvalidDht(String),
+    InvalidDqt(String),
+    InvalidSof(String),
+    InvalidSos(String),
+    /// Other malformed segments or entropy-coded data.
+    InvalidData(String),
+    /// The decoding is aborted by the cancellation flag.
+    Cancelled,
+}
+
+impl fmt::Display for JpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            JpegError::Io(e) => write!(f, "{e}"),
+            JpegError::Truncated => write!(f, "unexpected end of file"),
+            JpegError::UnsupportedMarker(byte) => write!(f, "Invalid marker: 0x{byte:02X}"),
+            JpegError::UnexpectedEoi => write!(f, "unexpected EOI"),
+            JpegError::MissingSof => write!(f, "SOF not found"),
+            JpegError::UnsupportedEncoding(n) => {
+                let process = match n {
+                    1 | 9 => "extended sequential DCT",
+                    2 | 10 => "progressive DCT",
+                    3 | 11 => "lossless",
+                    5 | 13 => "differential sequential DCT",
+                    6 | 14 => "differential progressive DCT",
+                    _ => "differential lossless",
+                };
+                let coding = if *n >= 9 { "arithmetic" } else { "huffman" };
+                write!(
+                    f,
+                    "unsupported encoding: SOF{n} ({process}, {coding} coding)"
+                )
+            }
+            JpegError::UnsupportedPrecision(precision) => {
+                write!(f, "unsupported sample precision: {precision} bits")
+            }
+            JpegError::InvalidQuantTableId(id) => write!(f, "invalid quantization table id: {id}"),
+            JpegError::MissingHuffmanTable(class) => {
+                write!(f, "huffman table not found: {class:?}")
+            }
+            JpegError::InvalidDht(msg)
+            | JpegError::InvalidDqt(msg)
+            | JpegError::InvalidSof(msg)
+            | JpegError::InvalidSos(msg)
+            | JpegError::InvalidData(msg) => write!(f, "{msg}"),
+            JpegError::Cancelled => write!(f, "decoding cancelled"),
+        }
+    }
+}
+
+impl core::error::Error for JpegError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            JpegError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for JpegError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => JpegError::Truncated,
+            _ => JpegError::Io(e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<JpegError> for io::Error {
+    fn from(e: JpegError) -> Self {
+        match e {
+            JpegError::Io(e) => e,
+            JpegError::Truncated => io::ErrorKind::UnexpectedEof.into(),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+
+    #[test]
+    fn test_error() {
+        let data = std::fs::read("data/autumn.jpg").expect("failed to read file");
+        let err = Decoder::new(&data[..100]).read().err().unwrap();
+        assert!(matches!(err, JpegError::Truncated), "{err}");
+
+        // EOI right after SOI
+        let err = Decoder::new(&b"\xFF\xD8\xFF\xD9"[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::UnexpectedEoi));
+        assert_eq!(err.to_string(), "unexpected EOI");
+
+        // DQT but no SOF before SOS
+        let mut data = b"\xFF\xD8\xFF\xDB\x00\x43\x00".to_vec();
+        data.extend([1; 64]);
+        data.extend(b"\xFF\xDA");
+        let err = Decoder::new(&data[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::MissingSof), "{err}");
+
+        // quantization tables 1, 1 without 0
+        let mut data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        let dqt = data.windows(2).position(|w| w == [0xFF, 0xDB]).unwrap();
+        data[dqt + 4] = 1;
+        let err = Decoder::new(&data[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::InvalidQuantTableId(0)), "{err}");
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        let mut data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        for (marker, message) in [
+            (
+                0xC9,
+                "unsupported encoding: SOF9 (extended sequential DCT, arithmetic coding)",
+            ),
+            (
+                0xC3,
+                "unsupported encoding: SOF3 (lossless, huffman coding)",
+            ),
+        ] {
+            data[sof + 1] = marker;
+            let err = Decoder::new(&data[..]).read().err().unwrap();
+            assert!(matches!(err, JpegError::UnsupportedEncoding(_)), "{err}");
+            assert_eq!(err.to_string(), message);
+            let err = Decoder::new(&data[..]).probe().unwrap_err();
+            assert!(matches!(err, JpegError::UnsupportedEncoding(_)), "{err}");
+        }
+    }
+}
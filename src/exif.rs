@@ -0,0 +1,197 @@
+use crate::{marker::Marker, prelude::*, source::Input, Decoder, Result};
+use tracing::debug;
+
+/// The EXIF metadata in APP1.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Exif {
+    /// The orientation of IFD0 in 1..=8, where 1 is upright.
+    pub orientation: Option<u16>,
+    /// The JPEG thumbnail of IFD1.
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+impl<R: Input> Decoder<R> {
+    /// Read the JPEG thumbnail embedded in IFD1 of the EXIF in APP1,
+    /// skipping the segments before it, without decoding the image.
+    ///
+    /// Return `None` if there is no thumbnail before the frame, or the headers are malformed.
+    /// The headers are consumed like [`Self::peek_dimensions`].
+    pub fn exif_thumbnail(&mut self) -> Option<&[u8]> {
+        if self.exif_thumbnail.is_none() {
+            self.exif_thumbnail = self.find_exif_thumbnail().ok().flatten();
+        }
+        self.exif_thumbnail.as_deref()
+    }
+
+    fn find_exif_thumbnail(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.next_marker()? {
+                Marker::SOI | Marker::TEM => {}
+                Marker::APP(1) => {
+                    if let Some(thumbnail) = self.read_app1()?.and_then(|exif| exif.thumbnail) {
+                        return Ok(Some(thumbnail));
+                    }
+                }
+                Marker::SOF0
+                | Marker::SOF2
+                | Marker::SOF(_)
+                | Marker::SOS
+                | Marker::EOI
+                | Marker::RST(_) => return Ok(None),
+                _ => self.skip_segment()?,
+            }
+        }
+    }
+
+    /// Read APP1, returning the [`Exif`] if present.
+    pub(crate) fn read_app1(&mut self) -> Result<Option<Exif>> {
+        let len = self.read_u16()?;
+        debug!(len, "read section APP1");
+        let mut data = vec![0; (len as usize).saturating_sub(2)];
+        self.read_exact(&mut data)?;
+        let Some(tiff) = data.strip_prefix(b"Exif\0\0") else {
+            return Ok(None);
+        };
+        let exif = Exif {
+            orientation: read_ifd(tiff, 0, 0x0112).map(|v| v as u16),
+            thumbnail: find_thumbnail(tiff).map(|thumbnail| thumbnail.to_vec()),
+        };
+        debug!(?exif.orientation, "EXIF");
+        Ok(Some(exif))
+    }
+}
+
+/// Find the JPEGInterchangeFormat and JPEGInterchangeFormatLength in IFD1 of a TIFF structure.
+fn find_thumbnail(tiff: &[u8]) -> Option<&[u8]> {
+    let offset = read_ifd(tiff, 1, 0x0201)? as usize;
+    let len = read_ifd(tiff, 1, 0x0202)? as usize;
+    debug!(offset, len, "EXIF thumbnail");
+    tiff.get(offset..offset.checked_add(len)?)
+}
+
+/// Read the value of a SHORT or LONG `tag` in the `n`-th IFD of a TIFF structure.
+fn read_ifd(tiff: &[u8], n: usize, tag: u16) -> Option<u32> {
+    let big_endian = match tiff.get(..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+    let u16_at = |i: usize| {
+        let bytes = tiff.get(i..i.checked_add(2)?)?.try_into().unwrap();
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let u32_at = |i: usize| {
+        let bytes = tiff.get(i..i.checked_add(4)?)?.try_into().unwrap();
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    };
+    // each entry is tag, type, count and value in 12 bytes, followed by the next IFD
+    let mut ifd = u32_at(4)? as usize;
+    for _ in 0..n {
+        ifd = u32_at(ifd + 2 + 12 * u16_at(ifd)? as usize)? as usize;
+        if ifd == 0 {
+            return None;
+        }
+    }
+    for k in 0..u16_at(ifd)? as usize {
+        let entry = ifd + 2 + 12 * k;
+        if u16_at(entry)? == tag {
+            return match u16_at(entry + 2)? {
+                3 => u16_at(entry + 8).map(u32::from),
+                _ => u32_at(entry + 8),
+            };
+        }
+    }
+    None
+}
+
+/// Transform the `width * height` pixels of an image by EXIF `orientation` to be upright,
+/// returning the new width, height and pixels.
+pub(crate) fn apply_orientation<P: Copy>(
+    width: u32,
+    height: u32,
+    pixels: Vec<P>,
+    orientation: u16,
+) -> (u32, u32, Vec<P>) {
+    let (w, h) = (width as usize, height as usize);
+    // the source pixel of (x, y) in the output
+    let source: fn(usize, usize, usize, usize) -> (usize, usize) = match orientation {
+        2 => |x, y, w, _| (w - 1 - x, y),
+        3 => |x, y, w, h| (w - 1 - x, h - 1 - y),
+        4 => |x, y, _, h| (x, h - 1 - y),
+        5 => |x, y, _, _| (y, x),
+        6 => |x, y, _, h| (y, h - 1 - x),
+        7 => |x, y, w, h| (w - 1 - y, h - 1 - x),
+        8 => |x, y, w, _| (w - 1 - y, x),
+        _ => return (width, height, pixels),
+    };
+    let (out_w, out_h) = if orientation >= 5 { (h, w) } else { (w, h) };
+    let mut out = Vec::with_capacity(pixels.len());
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let (sx, sy) = source(x, y, w, h);
+            out.push(pixels[sy * w + sx]);
+        }
+    }
+    (out_w as u32, out_h as u32, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::open;
+
+    #[test]
+    fn test_exif_thumbnail() {
+        let decoder = |path| Decoder::new(std::fs::File::open(path).expect("failed to read file"));
+        // data/comment.jpg in APP1 of data/gray.jpg
+        let thumbnail = decoder("data/exif.jpg").exif_thumbnail().unwrap().to_vec();
+        assert_eq!(thumbnail, std::fs::read("data/comment.jpg").unwrap());
+        let reader = Decoder::new(&thumbnail[..]).read().unwrap();
+        assert_eq!((reader.width(), reader.height()), (16, 16));
+        let reader = open("data/exif.jpg");
+        assert_eq!((reader.width(), reader.height()), (67, 45));
+
+        assert_eq!(decoder("data/gray.jpg").exif_thumbnail(), None);
+    }
+
+    #[test]
+    fn test_orientation() {
+        // 1 2 3
+        // 4 5 6
+        let pixels = vec![1, 2, 3, 4, 5, 6];
+        let expected = [
+            (3, vec![1, 2, 3, 4, 5, 6]),
+            (3, vec![3, 2, 1, 6, 5, 4]),
+            (3, vec![6, 5, 4, 3, 2, 1]),
+            (3, vec![4, 5, 6, 1, 2, 3]),
+            (2, vec![1, 4, 2, 5, 3, 6]),
+            (2, vec![4, 1, 5, 2, 6, 3]),
+            (2, vec![6, 3, 5, 2, 4, 1]),
+            (2, vec![3, 6, 2, 5, 1, 4]),
+        ];
+        for (orientation, (width, out)) in (1..=8).zip(expected) {
+            let (w, h, p) = apply_orientation(3, 2, pixels.clone(), orientation);
+            assert_eq!(
+                (w, h, p),
+                (width, 6 / width, out),
+                "orientation {orientation}"
+            );
+        }
+
+        // data/gray.jpg with orientation 6 in EXIF
+        let reader = open("data/orientation.jpg");
+        assert_eq!(reader.exif().and_then(|exif| exif.orientation), Some(6));
+        let (width, height, rotated) = reader.with_auto_orient(true).decode_to_rgb8().unwrap();
+        let (_, _, pixels) = open("data/gray.jpg").decode_to_rgb8().unwrap();
+        assert_eq!((width, height), (45, 67));
+        assert_eq!(rotated, apply_orientation(67, 45, pixels.clone(), 6).2);
+        let (width, _, _) = open("data/orientation.jpg").decode_to_rgb8().unwrap();
+        assert_eq!(width, 67);
+    }
+}
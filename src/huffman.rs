@@ -1,9 +1,6 @@
-use super::{error, Decoder};
+use crate::{prelude::*, source::Input, Decoder, JpegError, Result};
+use core::fmt::Debug;
 use num_enum::TryFromPrimitive;
-use std::{
-    fmt::Debug,
-    io::{Read, Result},
-};
 use tracing::debug;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -21,61 +18,152 @@ pub enum HuffmanTableClass {
     AC1 = 0x11,
 }
 
+/// A two-level lookup table of huffman codes.
+///
+/// Codes of at most 8 bits are decoded by the first 8 bits of the stream,
+/// and the longer ones by a sub-table of the next 8 bits.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HuffmanTree {
-    len_val: [(u8, u8); 1 << 16],
+    /// (len, val) indexed by the first 8 bits, or ([`Self::SUB`], index of the sub-table).
+    fast: [(u8, u8); 1 << 8],
+    /// (len, val) indexed by the next 8 bits.
+    slow: Vec<[(u8, u8); 1 << 8]>,
 }
 
 impl HuffmanTree {
+    /// The length of a `fast` entry pointing to a sub-table.
+    const SUB: u8 = 0xFF;
+
     pub const fn new() -> Self {
         HuffmanTree {
-            len_val: [(0, 0); 1 << 16],
+            fast: [(0, 0); 1 << 8],
+            slow: Vec::new(),
         }
     }
 
     pub fn insert(&mut self, code: u16, len: u8, val: u8) {
         assert!(len <= 16);
-        let base = (code << (16 - len)) as usize;
-        let range = base..=(base | ((1 << (16 - len)) - 1));
-        self.len_val[range].fill((len, val));
+        let base = (code as u32) << (16 - len);
+        let range = |base: u32, bits: u8| base as usize..=(base | ((1 << bits) - 1)) as usize;
+        if len <= 8 {
+            self.fast[range(base >> 8, 8 - len)].fill((len, val));
+            return;
+        }
+        let prefix = (base >> 8) as usize;
+        if self.fast[prefix].0 != Self::SUB {
+            self.fast[prefix] = (Self::SUB, self.slow.len() as u8);
+            self.slow.push([(0, 0); 1 << 8]);
+        }
+        let sub = self.fast[prefix].1 as usize;
+        self.slow[sub][range(base & 0xFF, 16 - len)].fill((len, val));
+    }
+
+    /// Build the canonical codes of DHT from the number of codes of each length 1..=16,
+    /// and the values in the order of codes.
+    pub fn from_counts(counts: &[u8; 16], values: &[u8]) -> Result<Self> {
+        let total = counts.iter().map(|&c| c as usize).sum::<usize>();
+        if values.len() != total {
+            return Err(JpegError::InvalidDht(format!(
+                "expect {total} huffman values, found {}",
+                values.len()
+            )));
+        }
+        let mut code = 0u32;
+        let mut h = 0;
+        let mut map = HuffmanTree::new();
+        let mut values = values.iter();
+        for &count in counts {
+            code *= 2;
+            h += 1;
+            // the codes of length `h` are code..code + count
+            if code + count as u32 > 1 << h {
+                return Err(JpegError::InvalidDht(format!(
+                    "too many huffman codes of length {h}: {count}"
+                )));
+            }
+            for &value in values.by_ref().take(count as usize) {
+                map.insert(code as u16, h, value);
+                code += 1;
+            }
+        }
+        Ok(map)
     }
 
     /// Decode a value from the stream. Return (len, val).
     pub fn get(&self, code: u16) -> (u8, u8) {
-        self.len_val[code as usize]
+        match self.fast[(code >> 8) as usize] {
+            (Self::SUB, sub) => self.slow[sub as usize][(code & 0xFF) as usize],
+            len_val => len_val,
+        }
     }
 }
 
-impl<R: Read> Decoder<R> {
+impl Default for HuffmanTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The huffman tables in effect, by class and id.
+///
+/// A table defined again, e.g. between scans, replaces the earlier one.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HuffmanTables([Option<HuffmanTree>; 4]);
+
+impl HuffmanTables {
+    fn index(class: HuffmanTableClass) -> usize {
+        match class {
+            HuffmanTableClass::DC0 => 0,
+            HuffmanTableClass::DC1 => 1,
+            HuffmanTableClass::AC0 => 2,
+            HuffmanTableClass::AC1 => 3,
+        }
+    }
+
+    /// Define `table`, replacing the one of the same class and id.
+    pub fn insert(&mut self, table: HuffmanTable) {
+        self.0[Self::index(table.class)] = Some(table.map);
+    }
+
+    /// The table of `class` defined last.
+    pub fn get(&self, class: HuffmanTableClass) -> Result<&HuffmanTree> {
+        self.0[Self::index(class)]
+            .as_ref()
+            .ok_or(JpegError::MissingHuffmanTable(class))
+    }
+}
+
+impl Extend<HuffmanTable> for HuffmanTables {
+    fn extend<T: IntoIterator<Item = HuffmanTable>>(&mut self, tables: T) {
+        for table in tables {
+            self.insert(table);
+        }
+    }
+}
+
+impl<R: Input> Decoder<R> {
     /// Read the next marker.
     pub fn read_huffman_table(&mut self) -> Result<Vec<HuffmanTable>> {
-        let mut len = self.read_u16()?;
+        let len = self.read_u16()?;
         debug!(len, "read section DHT");
-        len -= 2;
+        let invalid_len = || JpegError::InvalidDht(format!("invalid DHT length: {len}"));
+        let mut rest = len.checked_sub(2).ok_or_else(invalid_len)?;
         let mut tables = vec![];
-        while len != 0 {
+        while rest != 0 {
             let byte = self.read_byte()?;
-            let class = HuffmanTableClass::try_from(byte)
-                .map_err(|_| error(format!("invalid huffman table class: 0x{byte:02x}")))?;
+            let class = HuffmanTableClass::try_from(byte).map_err(|_| {
+                JpegError::InvalidDht(format!("invalid huffman table class: 0x{byte:02x}"))
+            })?;
             debug!(?class, "read huffman table");
             let mut counts = [0; 16];
-            self.reader.read_exact(&mut counts)?;
+            self.read_exact(&mut counts)?;
             debug!(?counts, "leaf nodes");
-            len -= 1 + 16;
+            rest = rest.checked_sub(1 + 16).ok_or_else(invalid_len)?;
 
-            let mut code = 0;
-            let mut h = 0;
-            let mut map = HuffmanTree::new();
-            for count in counts {
-                code *= 2;
-                h += 1;
-                for _ in 0..count {
-                    let value = self.read_byte()?;
-                    map.insert(code, h, value);
-                    code += 1;
-                }
-                len -= count as u16;
-            }
+            let mut values = vec![0; counts.iter().map(|&c| c as usize).sum()];
+            self.read_exact(&mut values)?;
+            rest = (rest.checked_sub(values.len() as u16)).ok_or_else(invalid_len)?;
+            let map = HuffmanTree::from_counts(&counts, &values)?;
             tables.push(HuffmanTable { class, map });
         }
         Ok(tables)
@@ -121,4 +209,77 @@ mod tests {
             }]
         );
     }
+
+    #[test]
+    fn test_from_counts() {
+        let mut counts = [0; 16];
+        counts[1..9].copy_from_slice(&[1, 5, 1, 1, 1, 1, 1, 1]);
+        let map = HuffmanTree::from_counts(&counts, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        let file = std::fs::File::open("data/autumn.jpg").expect("failed to read file");
+        let mut decoder = Decoder::new(file);
+        while decoder.next_marker().expect("failed to read marker") != Marker::DHT {}
+        let dhts = decoder.read_huffman_table().expect("failed to read DHT");
+        assert_eq!(map.unwrap(), dhts[0].map);
+
+        let err = HuffmanTree::from_counts(&counts, &[0; 11]).unwrap_err();
+        assert!(matches!(err, JpegError::InvalidDht(_)), "{err}");
+    }
+
+    #[test]
+    fn test_over_subscribed() {
+        // 1 code of length 1, then 3 of length 2 while only 2 are left
+        let mut dht = vec![0, 2 + 1 + 16 + 4, 0x00, 1, 3];
+        dht.extend([0; 14]);
+        dht.extend([0, 1, 2, 3]);
+        let err = Decoder::new(&dht[..]).read_huffman_table().unwrap_err();
+        assert!(matches!(err, JpegError::InvalidDht(_)), "{err}");
+
+        // 1 less is fine
+        dht[4] = 2;
+        dht[1] -= 1;
+        dht.pop();
+        assert!(Decoder::new(&dht[..]).read_huffman_table().is_ok());
+    }
+
+    #[test]
+    fn test_redefined() {
+        // DC0 with the values reversed, then the table of the image
+        let decode = |path| {
+            let file = std::fs::File::open(path).expect("failed to read file");
+            Decoder::new(file).read().unwrap().decode_to_rgb8().unwrap()
+        };
+        assert_eq!(
+            decode("data/dht_redefined.jpg"),
+            decode("data/pattern_420.jpg")
+        );
+
+        let mut tables = HuffmanTables::default();
+        let table = |val| {
+            let mut map = HuffmanTree::new();
+            map.insert(0, 1, val);
+            HuffmanTable {
+                class: HuffmanTableClass::DC0,
+                map,
+            }
+        };
+        tables.extend([table(1), table(2)]);
+        assert_eq!(tables.get(HuffmanTableClass::DC0).unwrap().get(0), (1, 2));
+        let err = tables.get(HuffmanTableClass::AC0).unwrap_err();
+        assert!(matches!(err, JpegError::MissingHuffmanTable(_)), "{err}");
+    }
+
+    #[test]
+    fn test_long_codes() {
+        let mut map = HuffmanTree::new();
+        map.insert(0b0, 1, 1);
+        map.insert(0b100000000, 9, 2);
+        map.insert(0xFFFE, 16, 3);
+        assert_eq!(map.get(0x7FFF), (1, 1));
+        assert_eq!(map.get(0b100000000 << 7), (9, 2));
+        assert_eq!(map.get(0b100000000 << 7 | 0x7F), (9, 2));
+        assert_eq!(map.get(0xFFFE), (16, 3));
+        // not defined
+        assert_eq!(map.get(0xFFFF).0, 0);
+        assert_eq!(map.get(0xC000).0, 0);
+    }
 }
@@ -0,0 +1,95 @@
+//! The ICC profile split into chunks in APP2.
+
+use crate::{prelude::*, source::Input, Decoder, Result};
+use tracing::debug;
+
+/// A chunk of the ICC profile in APP2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IccChunk {
+    /// The sequence number from 1.
+    index: u8,
+    /// The number of chunks.
+    count: u8,
+    data: Vec<u8>,
+}
+
+impl<R: Input> Decoder<R> {
+    /// Read APP2, returning the [`IccChunk`] if present.
+    pub(crate) fn read_app2(&mut self) -> Result<Option<IccChunk>> {
+        let len = self.read_u16()?;
+        debug!(len, "read section APP2");
+        let mut data = vec![0; (len as usize).saturating_sub(2)];
+        self.read_exact(&mut data)?;
+        let Some(&[index, count, ref payload @ ..]) = data.strip_prefix(b"ICC_PROFILE\0") else {
+            return Ok(None);
+        };
+        debug!(index, count, len = payload.len(), "ICC profile chunk");
+        Ok(Some(IccChunk {
+            index,
+            count,
+            data: payload.to_vec(),
+        }))
+    }
+}
+
+/// Concatenate the chunks in the order of their sequence numbers.
+///
+/// Return `None` if a chunk is missing, or the numbers are inconsistent.
+/// The duplicates of a chunk are ignored.
+pub(crate) fn assemble(chunks: &[IccChunk]) -> Option<Vec<u8>> {
+    let count = chunks.first()?.count as usize;
+    let mut slots = vec![None; count];
+    for chunk in chunks {
+        if chunk.count as usize != count || chunk.index == 0 || chunk.index as usize > count {
+            debug!(chunk.index, chunk.count, "invalid ICC profile chunk");
+            return None;
+        }
+        slots[chunk.index as usize - 1].get_or_insert(&chunk.data);
+    }
+    let mut profile = vec![];
+    for slot in slots {
+        profile.extend_from_slice(slot?);
+    }
+    Some(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Insert the APP2 segments of `chunks` after SOI.
+    fn with_chunks(data: &[u8], chunks: &[(u8, u8, &[u8])]) -> Vec<u8> {
+        let mut out = data[..2].to_vec();
+        for (index, count, payload) in chunks {
+            out.extend_from_slice(&[0xFF, 0xE2]);
+            out.extend_from_slice(&(2 + 14 + payload.len() as u16).to_be_bytes());
+            out.extend_from_slice(b"ICC_PROFILE\0");
+            out.extend_from_slice(&[*index, *count]);
+            out.extend_from_slice(payload);
+        }
+        out.extend_from_slice(&data[2..]);
+        out
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let profile = |chunks: &[(u8, u8, &[u8])]| {
+            let data = with_chunks(&data, chunks);
+            let reader = Decoder::new(&data[..]).read().unwrap();
+            reader.icc_profile().map(|p| p.to_vec())
+        };
+        let expected = Some(b"abcdef".to_vec());
+        assert_eq!(profile(&[(1, 2, b"abc"), (2, 2, b"def")]), expected);
+        // out of order, with a duplicate
+        assert_eq!(
+            profile(&[(2, 2, b"def"), (1, 2, b"abc"), (2, 2, b"xyz")]),
+            expected
+        );
+        // missing or inconsistent
+        assert_eq!(profile(&[(1, 2, b"abc")]), None);
+        assert_eq!(profile(&[(1, 2, b"abc"), (2, 3, b"def")]), None);
+        assert_eq!(profile(&[(0, 1, b"abc")]), None);
+        assert_eq!(profile(&[]), None);
+    }
+}
@@ -0,0 +1,91 @@
+//! Interop with the [`image`](::image) crate.
+
+use crate::{mcu::McuReader, Decoder, JpegError, Result};
+use ::image::{
+    error::DecodingError, ColorType, ImageDecoder, ImageError, ImageFormat, ImageResult,
+};
+use std::io::Read;
+
+/// A JPEG decoder for the [`image`](::image) crate, in RGB or grayscale.
+///
+/// CMYK and YCCK images are converted to RGB.
+pub struct JpegDecoder<R: Read> {
+    reader: McuReader<R>,
+}
+
+impl<R: Read> JpegDecoder<R> {
+    /// Read the headers of the image.
+    pub fn new(reader: R) -> Result<Self> {
+        Ok(JpegDecoder {
+            reader: Decoder::new(reader).read()?,
+        })
+    }
+}
+
+impl<R: Read> ImageDecoder for JpegDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.reader.width() as u32, self.reader.height() as u32)
+    }
+
+    fn color_type(&self) -> ColorType {
+        match self.reader.component_number() {
+            1 => ColorType::L8,
+            _ => ColorType::Rgb8,
+        }
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        assert_eq!(buf.len() as u64, self.total_bytes());
+        let gray = self.color_type() == ColorType::L8;
+        let row_bytes = self.reader.width() as usize * if gray { 1 } else { 3 };
+        self.reader.for_each_row(|y, row| {
+            let out = &mut buf[y * row_bytes..(y + 1) * row_bytes];
+            if gray {
+                for (out, p) in out.iter_mut().zip(row) {
+                    *out = p.r;
+                }
+            } else {
+                for (out, p) in out.chunks_exact_mut(3).zip(row) {
+                    out.copy_from_slice(&[p.r, p.g, p.b]);
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+impl From<JpegError> for ImageError {
+    fn from(e: JpegError) -> Self {
+        match e {
+            JpegError::Io(e) => ImageError::IoError(e),
+            e => ImageError::Decoding(DecodingError::new(ImageFormat::Jpeg.into(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::open;
+    use ::image::DynamicImage;
+
+    #[test]
+    fn test_image_decoder() {
+        let file = |path| std::fs::File::open(path).expect("failed to read file");
+        let image = DynamicImage::from_decoder(JpegDecoder::new(file("data/city.jpg")).unwrap());
+        let image = image.unwrap().into_rgb8();
+        let (width, height, pixels) = open("data/city.jpg").decode_to_rgb8().unwrap();
+        assert_eq!(image.dimensions(), (width, height));
+        let p = pixels[3 * width as usize + 5];
+        assert_eq!(image.get_pixel(5, 3).0, [p.r, p.g, p.b]);
+
+        let decoder = JpegDecoder::new(file("data/gray.jpg")).unwrap();
+        assert_eq!(decoder.color_type(), ColorType::L8);
+        let image = DynamicImage::from_decoder(decoder).unwrap();
+        assert_eq!((image.width(), image.height()), (67, 45));
+    }
+}
@@ -0,0 +1,96 @@
+//! Classify an image from its headers.
+
+use crate::{marker::Marker, source::Input, Decoder, JpegError, Result};
+
+/// The coding process of the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Baseline sequential DCT (SOF0).
+    Baseline,
+    /// Progressive DCT (SOF2).
+    Progressive,
+}
+
+/// The frame properties read by [`Decoder::probe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub encoding: Encoding,
+    pub width: u16,
+    pub height: u16,
+    /// The number of components: 1 for grayscale, 3 for color, 4 for CMYK or YCCK.
+    pub components: u8,
+    /// The horizontal and vertical ratio of the luma to the chroma resolution,
+    /// e.g. `(2, 2)` for 4:2:0, `(1, 1)` for 4:4:4 or grayscale.
+    pub subsampling: (u8, u8),
+    /// The sample precision in bits.
+    pub precision: u8,
+}
+
+impl<R: Input> Decoder<R> {
+    /// Read the frame header, skipping the segments before it, without decoding the image.
+    ///
+    /// The headers are consumed, so use another [`Decoder`] to decode the image.
+    pub fn probe(&mut self) -> Result<ImageInfo> {
+        loop {
+            let (encoding, sof) = match self.next_marker()? {
+                Marker::SOI | Marker::TEM => continue,
+                Marker::SOF0 => (Encoding::Baseline, self.read_start_of_frame_0()?),
+                Marker::SOF2 => (Encoding::Progressive, self.read_start_of_frame_2()?),
+                Marker::SOF(n) => return Err(JpegError::UnsupportedEncoding(n)),
+                Marker::SOS | Marker::EOI | Marker::RST(_) => return Err(JpegError::MissingSof),
+                _ => {
+                    self.skip_segment()?;
+                    continue;
+                }
+            };
+            let subsampling = match sof.component_infos.get(1) {
+                Some(chroma)
+                    if chroma.horizontal_sampling != 0 && chroma.vertical_sampling != 0 =>
+                {
+                    (
+                        sof.max_horizontal_sampling / chroma.horizontal_sampling,
+                        sof.max_vertical_sampling / chroma.vertical_sampling,
+                    )
+                }
+                _ => (1, 1),
+            };
+            return Ok(ImageInfo {
+                encoding,
+                width: sof.width,
+                height: sof.height,
+                components: sof.component_number,
+                subsampling,
+                precision: sof.precision,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        let probe = |path| {
+            let file = std::fs::File::open(path).expect("failed to read file");
+            let info = Decoder::new(file).probe().unwrap();
+            (info.encoding, info.components, info.subsampling)
+        };
+        use Encoding::*;
+        assert_eq!(probe("data/city.jpg"), (Baseline, 3, (1, 1)));
+        assert_eq!(probe("data/gray.jpg"), (Baseline, 1, (1, 1)));
+        assert_eq!(probe("data/pattern_420.jpg"), (Baseline, 3, (2, 2)));
+        assert_eq!(probe("data/pattern_422.jpg"), (Baseline, 3, (2, 1)));
+        assert_eq!(probe("data/pattern_411.jpg"), (Baseline, 3, (4, 1)));
+        assert_eq!(
+            probe("data/pattern_420_progressive.jpg"),
+            (Progressive, 3, (2, 2))
+        );
+        assert_eq!(probe("data/cmyk.jpg"), (Baseline, 4, (1, 1)));
+
+        let file = std::fs::File::open("data/autumn.jpg").expect("failed to read file");
+        let info = Decoder::new(file).probe().unwrap();
+        assert_eq!((info.width, info.height, info.precision), (1920, 1080, 8));
+    }
+}
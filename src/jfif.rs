@@ -0,0 +1,147 @@
+use crate::{prelude::*, source::Input, Decoder, JpegError, Result, RGB};
+use tracing::debug;
+
+/// The JFIF header in APP0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jfif {
+    /// Major and minor version.
+    pub version: (u8, u8),
+    /// Units of the density: 0 for aspect ratio only, 1 for dots per inch, 2 for dots per cm.
+    pub units: u8,
+    pub x_density: u16,
+    pub y_density: u16,
+    /// The uncompressed thumbnail after the header, if it is not 0x0.
+    pub thumbnail: Option<JfifThumbnail>,
+}
+
+/// An uncompressed RGB thumbnail of at most 255x255 in the JFIF header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JfifThumbnail {
+    pub width: u8,
+    pub height: u8,
+    /// The `width * height` pixels in row-major order.
+    pub pixels: Vec<RGB>,
+}
+
+impl<R: Input> Decoder<R> {
+    /// Read APP0, returning the [`Jfif`] header if present.
+    pub(crate) fn read_app0(&mut self) -> Result<Option<Jfif>> {
+        let len = self.read_u16()?;
+        debug!(len, "read section APP0");
+        let mut data = vec![0; (len as usize).saturating_sub(2)];
+        self.read_exact(&mut data)?;
+        let Some(header) = data.strip_prefix(b"JFIF\0") else {
+            return Ok(None);
+        };
+        if header.len() < 9 {
+            return Err(JpegError::InvalidData(format!(
+                "invalid JFIF length: {len}"
+            )));
+        }
+        let (width, height) = (header[7], header[8]);
+        let rgb = &header[9..];
+        if rgb.len() < width as usize * height as usize * 3 {
+            return Err(JpegError::InvalidData(format!(
+                "JFIF thumbnail {width}x{height} truncated: {} bytes",
+                rgb.len()
+            )));
+        }
+        let thumbnail = (width != 0 && height != 0).then(|| JfifThumbnail {
+            width,
+            height,
+            pixels: (rgb.chunks_exact(3))
+                .take(width as usize * height as usize)
+                .map(|p| RGB {
+                    r: p[0],
+                    g: p[1],
+                    b: p[2],
+                })
+                .collect(),
+        });
+        let jfif = Jfif {
+            version: (header[0], header[1]),
+            units: header[2],
+            x_density: u16::from_be_bytes([header[3], header[4]]),
+            y_density: u16::from_be_bytes([header[5], header[6]]),
+            thumbnail,
+        };
+        debug!(?jfif);
+        Ok(Some(jfif))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::open;
+
+    #[test]
+    fn test_jfif() {
+        assert_eq!(
+            open("data/autumn.jpg").jfif(),
+            Some(&Jfif {
+                version: (1, 1),
+                units: 1,
+                x_density: 0,
+                y_density: 0,
+                thumbnail: None,
+            })
+        );
+        assert_eq!(
+            open("data/gray.jpg").jfif(),
+            Some(&Jfif {
+                version: (1, 2),
+                units: 0,
+                x_density: 1,
+                y_density: 1,
+                thumbnail: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        // replace APP0 after SOI with one of a 2x2 thumbnail
+        let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let mut app0 = vec![0xFF, 0xE0, 0, 16 + 12];
+        app0.extend(b"JFIF\0");
+        app0.extend([1, 2, 0, 0, 1, 0, 1, 2, 2]);
+        app0.extend([255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30]);
+        let data = [&data[..2], &app0, &data[4 + len..]].concat();
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        let rgb = |r, g, b| RGB { r, g, b };
+        assert_eq!(
+            reader.jfif().unwrap().thumbnail,
+            Some(JfifThumbnail {
+                width: 2,
+                height: 2,
+                pixels: vec![
+                    rgb(255, 0, 0),
+                    rgb(0, 255, 0),
+                    rgb(0, 0, 255),
+                    rgb(10, 20, 30)
+                ],
+            })
+        );
+        assert_eq!(reader.decode_to_rgb8().unwrap().2.len(), 67 * 45);
+
+        // without the last pixel
+        let mut short = data.clone();
+        short[5] -= 3;
+        let end = 2 + app0.len();
+        short.drain(end - 3..end);
+        let err = Decoder::new(&short[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::InvalidData(_)), "{err}");
+    }
+
+    #[test]
+    fn test_no_jfif() {
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        // strip APP0 after SOI
+        let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let data = [&data[..2], &data[4 + len..]].concat();
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        assert_eq!(reader.jfif(), None);
+    }
+}
@@ -1,83 +1,414 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(clippy::needless_range_loop)]
 
-use std::io::{BufRead, BufReader, Read, Result};
+extern crate alloc;
 
+pub mod adobe;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod bmp;
 mod decode;
+#[cfg(feature = "std")]
+pub mod encode;
+mod error;
+pub mod exif;
 pub mod huffman;
+mod icc;
+#[cfg(feature = "image")]
+pub mod image;
+pub mod info;
+pub mod jfif;
 mod marker;
 pub mod mcu;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod png;
+#[cfg(feature = "std")]
 pub mod ppm;
+pub mod progressive;
 pub mod quantization_table;
+mod simd;
+pub mod source;
 pub mod start_of_frame_0;
 mod start_of_scan;
+mod stats;
+pub mod tables;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 use tracing::debug;
 
+pub use self::decode::{
+    ColorSpace, McuCMYK, McuPixels, McuRGB, PixelFormat, PlanarImage, Upsampling, CMYK, RGB, RGB16,
+};
+pub use self::error::{JpegError, Result};
+use self::huffman::HuffmanTables;
 use self::marker::Marker;
-use self::mcu::McuReader;
+use self::mcu::{BitReader, McuReader};
+use self::prelude::*;
+use self::progressive::Coefficients;
+use self::quantization_table::QuantizationTable;
+use self::source::{Buffered, ByteSource, Input, Slice};
+use self::start_of_frame_0::StartOfFrameInfo;
+use self::start_of_scan::StartOfScanInfo;
+#[cfg(feature = "stats")]
+pub use self::stats::DecodeStats;
 
-pub struct Decoder<R: Read> {
-    reader: BufReader<R>,
+/// The items of the std prelude from `alloc`, for `no_std`.
+mod prelude {
+    pub(crate) use alloc::{format, string::String, vec, vec::Vec};
 }
 
-impl<R: Read> Decoder<R> {
+/// Decode a whole JPEG in memory like [`McuReader::decode_to_rgb8`].
+///
+/// Malformed input is reported as an error, so any panic from this function is a bug.
+/// This is the body of the fuzz target.
+pub fn decode_all(data: &[u8]) -> Result<(u32, u32, Vec<RGB>)> {
+    Decoder::from_bytes(data).read()?.decode_to_rgb8()
+}
+
+/// A whole image decoded in RGB, by `Image::try_from(&data[..])` in one shot.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// The `width * height` pixels in row-major order.
+    pub pixels: Vec<RGB>,
+}
+
+impl TryFrom<&[u8]> for Image {
+    type Error = JpegError;
+
+    /// Decode a JPEG in memory like [`decode_all`].
+    fn try_from(data: &[u8]) -> Result<Self> {
+        let (width, height, pixels) = decode_all(data)?;
+        Ok(Image {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl Image {
+    /// Write binary PPM to the file at `path`.
+    pub fn save_ppm(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut writer = ppm::PpmWriter::new(file, self.width, self.height)?;
+        writer.write_image(self.width, &self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Write PNG to the file at `path`.
+    pub fn save_png(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut writer = png::PngWriter::new(file, self.width, self.height)?;
+        writer.write_slice(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+pub struct Decoder<R: Input> {
+    reader: Buffered<R>,
+    /// The marker already consumed at the end of an entropy-coded segment.
+    marker: Option<u8>,
+    /// The thumbnail found by [`Self::exif_thumbnail`].
+    exif_thumbnail: Option<Vec<u8>>,
+    /// The number of bytes consumed from `reader`.
+    offset: u64,
+    /// The stats collected while reading the headers, handed to the [`McuReader`].
+    #[cfg(feature = "stats")]
+    stats: DecodeStats,
+}
+
+/// The quantization and huffman tables of a tables-only JPEG,
+/// for the image-only JPEGs of an abbreviated stream.
+#[derive(Debug, Default, Clone)]
+pub struct JpegTables {
+    quantization_tables: [Option<QuantizationTable>; 4],
+    huffman_tables: HuffmanTables,
+}
+
+impl<'a> Decoder<Slice<'a>> {
+    /// Decode a JPEG in memory, reading the slice in place.
+    pub fn from_bytes(data: &'a [u8]) -> Self {
+        Decoder::new(Slice(data))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Decoder<source::Mmap> {
+    /// Decode a file mapped into memory, without a read syscall for each block of data.
+    pub fn open_mmap(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the file is not expected to be modified while decoding
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Decoder::new(source::Mmap(mmap)))
+    }
+}
+
+impl<R: Input> Decoder<R> {
     pub fn new(reader: R) -> Self {
         Decoder {
-            reader: BufReader::new(reader),
+            reader: reader.into_source(),
+            marker: None,
+            exif_thumbnail: None,
+            offset: 0,
+            #[cfg(feature = "stats")]
+            stats: DecodeStats::default(),
+        }
+    }
+
+    pub fn read(self) -> Result<McuReader<R>> {
+        self.read_with_tables(&JpegTables::default())
+    }
+
+    /// Read a tables-only JPEG, which has DQT and DHT but no frame.
+    pub fn read_tables(mut self) -> Result<JpegTables> {
+        let mut tables = JpegTables::default();
+        loop {
+            match self.next_marker()? {
+                Marker::EOI => return Ok(tables),
+                Marker::DQT => {
+                    for qt in self.read_quantization_table()? {
+                        let id = qt.id as usize;
+                        tables.quantization_tables[id] = Some(qt);
+                    }
+                }
+                Marker::DHT => tables.huffman_tables.extend(self.read_huffman_table()?),
+                Marker::SOF0 | Marker::SOF2 | Marker::SOF(_) | Marker::SOS => {
+                    return Err(JpegError::InvalidData(
+                        "unexpected frame in tables-only JPEG".into(),
+                    ))
+                }
+                Marker::SOI | Marker::RST(_) | Marker::TEM => {}
+                _ => self.skip_segment()?,
+            }
         }
     }
 
-    pub fn read(mut self) -> Result<McuReader<R>> {
-        let mut quantization_tables = vec![];
-        let mut huffman_tables = vec![];
+    /// Read an image-only JPEG, with the tables from [`Self::read_tables`]
+    /// unless they are defined again in this image.
+    pub fn read_with_tables(mut self, tables: &JpegTables) -> Result<McuReader<R>> {
+        let mut quantization_tables = tables.quantization_tables.clone();
+        let mut huffman_tables = tables.huffman_tables.clone();
         let mut sof = None;
+        // the first scan of a baseline frame, if interleaved
+        let mut scan = None;
+        let mut scan_offset = None;
         let mut restart_interval = None;
+        let mut coefficients: Option<Coefficients> = None;
+        let mut jfif = None;
+        let mut comments = vec![];
+        let mut adobe = None;
+        let mut exif = None;
+        let mut icc_chunks = vec![];
+        let mut truncated = false;
         loop {
-            match self.next_marker()? {
-                Marker::EOI => return Err(error("unexpected EOI")),
-                Marker::DQT => quantization_tables.extend(self.read_quantization_table()?),
+            let marker = match self.next_marker() {
+                // keep the scans decoded so far
+                Err(JpegError::Truncated) if coefficients.is_some() => {
+                    truncated = true;
+                    break;
+                }
+                marker => marker?,
+            };
+            match marker {
+                Marker::EOI if coefficients.is_some() => break,
+                Marker::EOI => return Err(JpegError::UnexpectedEoi),
+                Marker::DQT => {
+                    for qt in self.read_quantization_table()? {
+                        let id = qt.id as usize;
+                        quantization_tables[id] = Some(qt);
+                    }
+                }
                 Marker::DHT => huffman_tables.extend(self.read_huffman_table()?),
                 Marker::SOF0 => sof = Some(self.read_start_of_frame_0()?),
-                Marker::DRI => restart_interval = Some(self.read_restart_interval()?),
-                Marker::APP(_) => self.skip_app()?,
-                Marker::SOS => break,
-                _ => {}
+                Marker::SOF2 => {
+                    let info = self.read_start_of_frame_2()?;
+                    if info.height == 0 {
+                        return Err(JpegError::InvalidSof(
+                            "height defined by DNL in progressive mode".into(),
+                        ));
+                    }
+                    coefficients = Some(Coefficients::new(&info));
+                    sof = Some(info);
+                }
+                Marker::SOF(n) => return Err(JpegError::UnsupportedEncoding(n)),
+                Marker::DRI => restart_interval = self.read_restart_interval()?,
+                Marker::APP(0) => jfif = jfif.or(self.read_app0()?),
+                Marker::APP(1) => exif = exif.or(self.read_app1()?),
+                Marker::APP(2) => icc_chunks.extend(self.read_app2()?),
+                Marker::APP(14) => adobe = adobe.or(self.read_app14()?),
+                Marker::APP(_) => self.skip_segment()?,
+                Marker::COM => comments.push(self.read_comment()?),
+                Marker::SOS => {
+                    let info = sof.as_ref().ok_or(JpegError::MissingSof)?;
+                    let sos = match self.read_start_of_scan(info) {
+                        Err(JpegError::Truncated) if coefficients.is_some() => {
+                            truncated = true;
+                            break;
+                        }
+                        sos => sos?,
+                    };
+                    scan_offset = scan_offset.or(Some(self.offset));
+                    if coefficients.is_none() {
+                        // baseline: decode the MCUs right from an interleaved scan
+                        if sos.component_number == info.component_number {
+                            scan = Some(sos);
+                            break;
+                        }
+                        if info.height == 0 {
+                            return Err(JpegError::InvalidSof(
+                                "height defined by DNL with non-interleaved scans".into(),
+                            ));
+                        }
+                        coefficients = Some(Coefficients::sequential(info));
+                    }
+                    // progressive or non-interleaved: accumulate the coefficients until EOI
+                    match self.read_scan(
+                        info,
+                        coefficients.as_mut().unwrap(),
+                        &sos,
+                        &huffman_tables,
+                        restart_interval,
+                    ) {
+                        Err(JpegError::Truncated) => {
+                            truncated = true;
+                            break;
+                        }
+                        r => r?,
+                    }
+                }
+                // markers without a segment
+                Marker::SOI | Marker::RST(_) | Marker::TEM => {}
+                _ => self.skip_segment()?,
+            }
+        }
+        let sof = sof.take().ok_or(JpegError::MissingSof)?;
+        for component in &sof.component_infos {
+            let id = component.quant_table_id;
+            if !matches!(quantization_tables.get(id as usize), Some(Some(_))) {
+                return Err(JpegError::InvalidQuantTableId(id));
             }
         }
-        for (i, qt) in quantization_tables.iter().enumerate() {
-            assert_eq!(qt.id, i as u8);
+        let mut reader = match coefficients {
+            Some(coefficients) => McuReader::with_coefficients(
+                self.reader,
+                self.offset,
+                sof,
+                quantization_tables,
+                coefficients,
+            ),
+            None => {
+                let sos = scan.expect("the headers end at the first scan");
+                if (sos.ss, sos.se, sos.ah_al) != (0x00, 0x3F, 0x00) {
+                    return Err(JpegError::InvalidSos(format!(
+                        "invalid baseline scan: Ss {}, Se {}, Ah/Al 0x{:02X}",
+                        sos.ss, sos.se, sos.ah_al
+                    )));
+                }
+                McuReader::new(
+                    self.reader,
+                    self.offset,
+                    sof,
+                    sos,
+                    quantization_tables,
+                    huffman_tables,
+                    restart_interval,
+                )?
+            }
+        };
+        reader.jfif = jfif;
+        reader.comments = comments;
+        reader.adobe = adobe;
+        reader.exif = exif;
+        reader.icc_profile = icc::assemble(&icc_chunks);
+        reader.scan_offset = scan_offset;
+        reader.truncated = truncated;
+        #[cfg(feature = "stats")]
+        {
+            reader.stats = self.stats;
         }
-        let sos = self.read_start_of_scan()?;
-        let sof = sof.take().expect("SOF not found");
-        let reader = McuReader::new(
-            self.reader,
-            sof,
-            sos,
-            quantization_tables,
-            huffman_tables,
-            restart_interval,
-        )?;
         Ok(reader)
     }
 
-    fn read_restart_interval(&mut self) -> Result<u16> {
+    /// Read the width, the height and the number of components from the frame header,
+    /// skipping the segments before it, for baseline and progressive images.
+    ///
+    /// The headers are consumed, so use another [`Decoder`] to decode the image.
+    pub fn peek_dimensions(&mut self) -> Result<(u16, u16, u8)> {
+        let info = self.probe()?;
+        Ok((info.width, info.height, info.components))
+    }
+
+    /// Read a scan of a progressive frame, or a non-interleaved one of a baseline frame,
+    /// into `coefficients`.
+    fn read_scan(
+        &mut self,
+        sof: &StartOfFrameInfo,
+        coefficients: &mut Coefficients,
+        sos: &StartOfScanInfo,
+        huffman_tables: &HuffmanTables,
+        restart_interval: Option<u16>,
+    ) -> Result<()> {
+        let mut reader = BitReader::new(&mut self.reader).with_start(self.offset);
+        let result = stats::timed!(
+            self.stats.huffman_ns,
+            coefficients.read_scan(&mut reader, sof, sos, huffman_tables, restart_interval)
+        );
+        self.offset += reader.bytes_read;
+        #[cfg(feature = "stats")]
+        {
+            self.stats.bytes_read += reader.bytes_read;
+        }
+        self.marker = reader.marker();
+        result
+    }
+
+    /// Read DRI, returning `None` for the interval 0 which disables restarts.
+    fn read_restart_interval(&mut self) -> Result<Option<u16>> {
         let len = self.read_u16()?;
         debug!(len, "read section DRI");
         if len != 4 {
-            return Err(error(format!("invalid DRI length: {len}")));
+            return Err(JpegError::InvalidData(format!("invalid DRI length: {len}")));
         }
         let interval = self.read_u16()?;
         debug!(interval, "restart interval");
-        Ok(interval)
+        Ok(Some(interval).filter(|&interval| interval != 0))
+    }
+
+    /// Read the payload of COM.
+    fn read_comment(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u16()?;
+        debug!(len, "read section COM");
+        let mut comment = vec![0; (len as usize).saturating_sub(2)];
+        self.read_exact(&mut comment)?;
+        Ok(comment)
     }
 
-    fn skip_app(&mut self) -> Result<()> {
+    /// Skip a segment by its length.
+    fn skip_segment(&mut self) -> Result<()> {
         let len = self.read_u16()?;
-        debug!(len, "read section APP?");
-        let mut len = len as usize - 2;
+        debug!(len, "skip section");
+        self.skip_bytes((len as usize).saturating_sub(2))
+    }
+
+    /// Skip `len` bytes.
+    fn skip_bytes(&mut self, mut len: usize) -> Result<()> {
         while len != 0 {
-            let l = self.reader.fill_buf()?.len().min(len);
-            self.reader.consume(l);
+            let buf = ByteSource::fill(&mut self.reader)?;
+            if buf.is_empty() {
+                return Err(JpegError::Truncated);
+            }
+            let l = buf.len().min(len);
+            ByteSource::consume(&mut self.reader, l);
+            self.offset += l as u64;
             len -= l;
         }
         Ok(())
@@ -85,19 +416,207 @@ impl<R: Read> Decoder<R> {
 
     /// Read a byte.
     fn read_byte(&mut self) -> Result<u8> {
-        let mut buf = [0u8];
-        self.reader.read_exact(&mut buf)?;
-        Ok(buf[0])
+        let byte = ByteSource::read_byte(&mut self.reader)?;
+        self.offset += 1;
+        Ok(byte)
     }
 
     /// Read a u16.
     fn read_u16(&mut self) -> Result<u16> {
         let mut buf = [0; 2];
-        self.reader.read_exact(&mut buf)?;
+        self.read_exact(&mut buf)?;
         Ok(u16::from_be_bytes(buf))
     }
+
+    /// Fill `buf` exactly.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        ByteSource::read_exact(&mut self.reader, buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
 }
 
-fn error(msg: impl Into<String>) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.into())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment() {
+        let file = std::fs::File::open("data/comment.jpg").expect("failed to read file");
+        let reader = Decoder::new(file).read().unwrap();
+        assert_eq!(reader.comments(), [b"Hello, JPEG".to_vec()]);
+        // the markers after COM are still aligned
+        assert_eq!(reader.decode_to_rgb8().unwrap().2.len(), 16 * 16);
+
+        let file = std::fs::File::open("data/gray.jpg").expect("failed to read file");
+        assert!(Decoder::new(file).read().unwrap().comments().is_empty());
+    }
+
+    #[test]
+    fn test_scan_offset() {
+        for path in [
+            "data/pattern_420.jpg",
+            "data/restart.jpg",
+            "data/pattern_420_progressive.jpg",
+        ] {
+            let data = std::fs::read(path).expect("failed to read file");
+            let mut reader = Decoder::new(&data[..]).read().unwrap();
+            // right after the first SOS
+            let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+            let len = u16::from_be_bytes([data[sos + 2], data[sos + 3]]) as usize;
+            assert_eq!(reader.scan_offset(), Some((sos + 2 + len) as u64), "{path}");
+            assert!(reader.bytes_consumed() >= (sos + 2 + len) as u64);
+            while reader.next().unwrap().is_some() {}
+            assert_eq!(reader.bytes_consumed(), data.len() as u64, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_skip_segments() {
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let (_, _, expected) = Decoder::from_bytes(&data)
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap();
+
+        // segments which look like EOI inside, right after SOI
+        let mut segments = vec![];
+        for marker in [0xEF, 0xFE, 0xF3] {
+            segments.extend([0xFF, marker, 0, 6, 0xFF, 0xD9, 0xFF, 0xD9]);
+        }
+        let data = [&data[..2], &segments, &data[2..]].concat();
+        let reader = Decoder::from_bytes(&data).read().unwrap();
+        assert_eq!(reader.comments(), [b"\xFF\xD9\xFF\xD9".to_vec()]);
+        assert_eq!(reader.decode_to_rgb8().unwrap().2, expected);
+    }
+
+    #[test]
+    fn test_peek_dimensions() {
+        let peek = |path| {
+            let file = std::fs::File::open(path).expect("failed to read file");
+            Decoder::new(file).peek_dimensions().unwrap()
+        };
+        assert_eq!(peek("data/autumn.jpg"), (1920, 1080, 3));
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        let mut decoder = Decoder::from_bytes(&data);
+        assert_eq!(decoder.peek_dimensions().unwrap(), (250, 118, 3));
+        assert_eq!(peek("data/gray.jpg"), (67, 45, 1));
+        assert_eq!(peek("data/pattern_420_progressive.jpg"), (67, 45, 3));
+        assert_eq!(peek("data/cmyk.jpg"), (35, 21, 4));
+
+        // SOF only, without the tables
+        let sof = b"\xFF\xD8\xFF\xC0\x00\x0B\x08\x00\x20\x00\x10\x01\x01\x11\x00";
+        assert_eq!(
+            Decoder::new(&sof[..]).peek_dimensions().unwrap(),
+            (16, 32, 1)
+        );
+    }
+
+    #[test]
+    fn test_dri_zero() {
+        let original = std::fs::read("data/city.jpg").expect("failed to read file");
+        let sos = original.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        let mut data = original[..sos].to_vec();
+        data.extend_from_slice(&[0xFF, 0xDD, 0x00, 0x04, 0x00, 0x00]);
+        data.extend_from_slice(&original[sos..]);
+        let (_, _, expected) = Decoder::new(&original[..])
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap();
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        assert_eq!(reader.decode_to_rgb8().unwrap().2, expected);
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        assert_eq!(reader.decode_parallel().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_abbreviated_stream() {
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        // split the segments before SOS into DQT/DHT and the others
+        let (mut tables, mut image) = (vec![0xFF, 0xD8], vec![0xFF, 0xD8]);
+        let mut i = 2;
+        while data[i + 1] != 0xDA {
+            let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+            let segment = &data[i..i + 2 + len];
+            match data[i + 1] {
+                0xDB | 0xC4 => tables.extend_from_slice(segment),
+                _ => image.extend_from_slice(segment),
+            }
+            i += 2 + len;
+        }
+        tables.extend_from_slice(&[0xFF, 0xD9]);
+        image.extend_from_slice(&data[i..]);
+
+        let tables = Decoder::from_bytes(&tables).read_tables().unwrap();
+        let reader = Decoder::from_bytes(&image).read_with_tables(&tables);
+        let expected = decode_all(&data).unwrap();
+        assert_eq!(reader.unwrap().decode_to_rgb8().unwrap(), expected);
+        // the tables are required
+        assert!(decode_all(&image).is_err());
+        // a normal JPEG is not tables-only
+        assert!(Decoder::from_bytes(&data).read_tables().is_err());
+    }
+
+    #[test]
+    fn test_image() {
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        let image = Image::try_from(&data[..]).unwrap();
+        let (width, height, pixels) = decode_all(&data).unwrap();
+        assert_eq!((image.width, image.height), (width, height));
+        assert_eq!(image.pixels, pixels);
+        assert!(Image::try_from(&data[..100]).is_err());
+
+        let path = std::env::temp_dir().join("jpeg_labs_test_image.ppm");
+        image.save_ppm(&path).unwrap();
+        let ppm = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = format!("P6\n{width} {height}\n255\n");
+        assert_eq!(&ppm[..header.len()], header.as_bytes());
+        assert_eq!(&ppm[header.len()..], RGB::as_bytes(&pixels));
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let (width, height, pixels) = decode_all(&data).unwrap();
+        assert_eq!((width, height, pixels.len()), (67, 45, 67 * 45));
+
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        let dht = data.windows(2).position(|w| w == [0xFF, 0xC4]).unwrap();
+        let patch = |i: usize, bytes: &[u8]| {
+            let mut data = data.clone();
+            data[i..i + bytes.len()].copy_from_slice(bytes);
+            data
+        };
+        // malformed files which used to panic or hang
+        for data in [
+            // zero height without DNL
+            patch(sof + 5, &[0, 0]),
+            // zero width
+            patch(sof + 7, &[0, 0]),
+            // a segment beyond the end
+            patch(2, &[0xFF, 0xF8, 0xFF, 0xFF]),
+            // DHT shorter than the tables
+            patch(dht + 2, &[0, 3]),
+        ] {
+            assert!(decode_all(&data).is_err());
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open_mmap() {
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        let reader = Decoder::open_mmap("data/city.jpg").unwrap().read().unwrap();
+        assert_eq!(
+            reader.decode_to_rgb8().unwrap(),
+            Decoder::from_bytes(&data)
+                .read()
+                .unwrap()
+                .decode_to_rgb8()
+                .unwrap()
+        );
+    }
 }
@@ -1,44 +1,127 @@
-use clap::Parser;
-use jpeg_labs::{ppm::PpmWriter, Decoder};
+use clap::{Parser, ValueEnum};
+use jpeg_labs::{
+    bmp::BmpWriter,
+    encode::Encoder,
+    png::PngWriter,
+    ppm::{PgmWriter, PpmWriter},
+    Decoder, RGB,
+};
+use std::{
+    fs::File,
+    io::{Result, Write},
+    path::Path,
+};
 
-/// JPEG to PPM.
+/// JPEG to PPM (PGM for grayscale), PNG, BMP or raw RGB, or re-encoded JPEG.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap()]
     file: String,
 
+    /// Output file. The format is chosen by its extension (.png, .bmp, .pgm, .raw or .ppm)
+    /// unless `--format` is given.
     #[clap(short, long)]
     output: String,
+
+    /// Output format.
+    #[clap(short, long, value_enum, conflicts_with = "reencode")]
+    format: Option<Format>,
+
+    /// Decode, then encode in baseline JPEG again.
+    #[clap(long)]
+    reencode: bool,
+
+    /// The quality of `--reencode` in 1..=100.
+    #[clap(short, long, requires = "reencode", value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Ppm,
+    Png,
+    Bmp,
+    Pgm,
+    /// 8-bit RGB samples without a header.
+    Raw,
+}
+
+impl Format {
+    /// The format of the extension of `path`: PPM, or PGM for grayscale, if unknown.
+    fn from_path(path: &Path, gray: bool) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("png") => Format::Png,
+            Some("bmp") => Format::Bmp,
+            Some("pgm") => Format::Pgm,
+            Some("raw") => Format::Raw,
+            _ if gray => Format::Pgm,
+            _ => Format::Ppm,
+        }
+    }
 }
 
 fn main() {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
-    let file = std::fs::File::open(args.file).expect("failed to open file");
-    let out = std::fs::File::create(args.output).expect("failed to create file");
+    let file = File::open(args.file).expect("failed to open file");
     let decoder = Decoder::new(file);
-    let mut decoder = decoder.read().unwrap();
-    let mut writer = PpmWriter::new(out, decoder.width() as _, decoder.height() as _).unwrap();
-    let mut mcus = Vec::with_capacity(decoder.mcu_width_num() as usize);
-    let mut height = decoder.height();
-    while let Some(mcu) = decoder.next().unwrap() {
-        mcus.push(mcu);
-        if mcus.len() == decoder.mcu_width_num() as usize {
-            for h in 0..decoder.mcu_height() {
-                let mut width = decoder.width() as usize;
-                for mcu in mcus.iter().flat_map(|mcu| mcu.line(h as usize)) {
-                    let len = mcu.len().min(width);
-                    writer.write_slice(&mcu[..len]).unwrap();
-                    width -= len;
-                }
-                height -= 1;
-                if height == 0 {
-                    break;
-                }
-            }
-            mcus.clear();
+    let reader = decoder.read().unwrap();
+    let gray = reader.component_number() == 1;
+    let format = (args.format).unwrap_or_else(|| Format::from_path(Path::new(&args.output), gray));
+    let result = if !args.reencode && format == Format::Pgm {
+        // the luma of a color image, rather than one of its channels
+        let (width, height, luma) = reader.decode_luma8().unwrap();
+        let out = File::create(&args.output).expect("failed to create file");
+        write_pgm(out, width, height, &luma)
+    } else {
+        let (width, height, pixels) = reader.decode_to_rgb8().unwrap();
+        let out = File::create(&args.output).expect("failed to create file");
+        match args.reencode {
+            true => Encoder::new(out, args.quality.unwrap_or(75))
+                .encode(width as u16, height as u16, &pixels)
+                .map(drop),
+            false => write(out, format, width, height, &pixels),
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("failed to write {}: {e}", args.output);
+        std::process::exit(1);
+    }
+}
+
+/// Write the grayscale image in PGM, flushing it at the end.
+fn write_pgm(out: File, width: u32, height: u32, luma: &[u8]) -> Result<()> {
+    let mut writer = PgmWriter::new(out, width, height)?;
+    writer.write_gray(luma)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Write the image in `format` other than PGM, flushing it at the end.
+fn write(out: File, format: Format, width: u32, height: u32, pixels: &[RGB]) -> Result<()> {
+    match format {
+        Format::Png => {
+            let mut writer = PngWriter::new(out, width, height)?;
+            writer.write_slice(pixels)?;
+            writer.finish()?;
+        }
+        Format::Bmp => {
+            let mut writer = BmpWriter::new(out, width, height)?;
+            writer.write_slice(pixels)?;
+            writer.finish()?;
+        }
+        Format::Pgm => unreachable!("written by write_pgm"),
+        Format::Ppm => {
+            let mut writer = PpmWriter::new(out, width, height)?;
+            writer.write_image(width, pixels)?;
+            writer.finish()?;
+        }
+        Format::Raw => {
+            let bytes: Vec<u8> = pixels.iter().flat_map(|p| [p.r, p.g, p.b]).collect();
+            (&out).write_all(&bytes)?;
         }
     }
+    Ok(())
 }
@@ -1,5 +1,4 @@
-use super::{error, Decoder};
-use std::io::{Read, Result};
+use crate::{source::Input, Decoder, JpegError, Result};
 use tracing::debug;
 
 /// JPEG markers
@@ -14,10 +13,17 @@ pub enum Marker {
     SOF0,
     /// Start Of Frame (Progressive DCT)
     SOF2,
+    /// Start Of Frame of the other coding processes, which are not supported:
+    /// 1, 3 and 5 to 7 with huffman coding, 9 to 11 and 13 to 15 with arithmetic coding
+    SOF(u8),
+    /// Define Arithmetic Coding conditioning
+    DAC,
     /// Define Huffman Table
     DHT,
     /// Define Quantization Table
     DQT,
+    /// Define Number of Lines, after the first scan
+    DNL,
     /// Define Restart Interval
     DRI,
     /// Start Of Scan
@@ -28,6 +34,10 @@ pub enum Marker {
     APP(u8),
     /// Comment
     COM,
+    /// JPEG extensions
+    JPG(u8),
+    /// For temporary private use in arithmetic coding, without a segment
+    TEM,
     /// End Of Image
     EOI,
 }
@@ -40,27 +50,37 @@ impl Marker {
 impl TryFrom<u8> for Marker {
     type Error = ();
 
-    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
         match value {
             0xC0 => Ok(Marker::SOF0),
             0xC2 => Ok(Marker::SOF2),
             0xC4 => Ok(Marker::DHT),
+            0xC1 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => Ok(Marker::SOF(value - 0xC0)),
+            0xCC => Ok(Marker::DAC),
             0xD0..=0xD7 => Ok(Marker::RST(value - 0xD0)),
             0xD8 => Ok(Marker::SOI),
             0xD9 => Ok(Marker::EOI),
             0xDA => Ok(Marker::SOS),
             0xDB => Ok(Marker::DQT),
+            0xDC => Ok(Marker::DNL),
             0xDD => Ok(Marker::DRI),
             0xE0..=0xEF => Ok(Marker::APP(value - 0xE0)),
+            0xF0..=0xFD => Ok(Marker::JPG(value - 0xF0)),
             0xFE => Ok(Marker::COM),
+            0x01 => Ok(Marker::TEM),
             _ => Err(()),
         }
     }
 }
 
-impl<R: Read> Decoder<R> {
+impl<R: Input> Decoder<R> {
     /// Read the next marker.
     pub fn next_marker(&mut self) -> Result<Marker> {
+        if let Some(byte) = self.marker.take() {
+            let marker = Marker::try_from(byte).map_err(|_| JpegError::UnsupportedMarker(byte))?;
+            debug!(?marker, "read marker after entropy-coded segment");
+            return Ok(marker);
+        }
         let mut count = 0;
         loop {
             let byte = self.read_byte()?;
@@ -68,17 +88,53 @@ impl<R: Read> Decoder<R> {
             if byte != Marker::PREFIX {
                 continue;
             }
-            let byte = self.read_byte()?;
+            let mut byte = self.read_byte()?;
             count += 1;
+            // any number of 0xFF fill bytes may precede a marker
+            while byte == Marker::PREFIX {
+                byte = self.read_byte()?;
+                count += 1;
+            }
             if byte == 0x00 {
                 continue;
             }
-            let marker = Marker::try_from(byte)
-                .map_err(|_| error(format!("Invalid marker: 0x{:02X}", byte)))?;
+            let marker = Marker::try_from(byte).map_err(|_| JpegError::UnsupportedMarker(byte))?;
             debug!(?marker, skip = count - 2, "read marker");
             return Ok(marker);
         }
     }
+
+    /// Walk the markers up to EOI without decoding, with the offset of each marker
+    /// and the length of its segment excluding the length field, 0 without a segment.
+    ///
+    /// The entropy-coded data after SOS is skipped, but the RSTn markers in it are included.
+    /// It ends after EOI or the first error.
+    pub fn markers(mut self) -> impl Iterator<Item = Result<(u64, Marker, u16)>> {
+        let mut done = false;
+        core::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let result = self.next_segment();
+            done = !matches!(result, Ok((_, marker, _)) if marker != Marker::EOI);
+            Some(result)
+        })
+    }
+
+    /// Read the next marker and skip its segment.
+    fn next_segment(&mut self) -> Result<(u64, Marker, u16)> {
+        let marker = self.next_marker()?;
+        let offset = self.offset - 2;
+        let len = match marker {
+            Marker::SOI | Marker::EOI | Marker::RST(_) | Marker::TEM => 0,
+            _ => {
+                let len = self.read_u16()?.saturating_sub(2);
+                self.skip_bytes(len as usize)?;
+                len
+            }
+        };
+        Ok((offset, marker, len))
+    }
 }
 
 #[cfg(test)]
@@ -104,4 +160,49 @@ mod tests {
             vec![SOI, APP(0), APP(0xC), DQT, DQT, SOF0, DHT, DHT, DHT, DHT, SOS, EOI]
         );
     }
+
+    #[test]
+    fn test_markers() {
+        use Marker::*;
+        let data = std::fs::read("data/autumn.jpg").expect("failed to read file");
+        let markers: Vec<_> = Decoder::new(&data[..])
+            .markers()
+            .collect::<Result<_>>()
+            .unwrap();
+        let kinds: Vec<_> = markers.iter().map(|&(_, marker, _)| marker).collect();
+        #[rustfmt::skip]
+        assert_eq!(
+            kinds,
+            vec![SOI, APP(0), APP(0xC), DQT, DQT, SOF0, DHT, DHT, DHT, DHT, SOS, EOI]
+        );
+        assert_eq!(markers[0], (0, SOI, 0));
+        assert_eq!(markers[1].0, 2);
+        assert_eq!(*markers.last().unwrap(), (data.len() as u64 - 2, EOI, 0));
+        for (offset, marker, len) in markers {
+            assert_eq!(data[offset as usize], 0xFF);
+            if marker != SOI && marker != EOI {
+                let at = offset as usize + 2;
+                let field = u16::from_be_bytes([data[at], data[at + 1]]);
+                assert_eq!(len, field - 2, "{marker:?}");
+            }
+        }
+
+        let markers = Decoder::new(&data[..100]).markers();
+        assert!(markers.last().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_fill_bytes() {
+        let mut decoder = Decoder::new(&b"\xFF\xD8\xFF\xFF\xFF\xDB\xFF\xFF"[..]);
+        assert_eq!(decoder.next_marker().unwrap(), Marker::SOI);
+        assert_eq!(decoder.next_marker().unwrap(), Marker::DQT);
+        // EOF in the fill bytes
+        let err = decoder.next_marker().unwrap_err();
+        assert!(matches!(err, JpegError::Truncated), "{err}");
+
+        // EOF in a segment header
+        let data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let err = Decoder::new(&data[..5]).read().err().unwrap();
+        assert!(matches!(err, JpegError::Truncated), "{err}");
+    }
 }
@@ -1,17 +1,29 @@
+#[cfg(feature = "std")]
+use rayon::prelude::*;
 use smallvec::SmallVec;
 
+#[cfg(feature = "stats")]
+use crate::stats::DecodeStats;
 use crate::{
-    decode::McuRGB,
-    error,
-    huffman::{HuffmanTable, HuffmanTree},
+    adobe::{Adobe, ColorTransform},
+    decode::{
+        to_u8, upsample_bilinear, ColorSpace, McuCMYK, McuPixels, McuRGB, PixelFormat, PlanarImage,
+        Upsampling, CMYK, RGB,
+    },
+    exif::{apply_orientation, Exif},
+    huffman::{HuffmanTables, HuffmanTree},
+    jfif::Jfif,
+    prelude::*,
+    progressive::Coefficients,
     quantization_table::QuantizationTable,
-    start_of_frame_0::StartOfFrameInfo,
+    source::{Buffered, ByteSource, Input},
+    start_of_frame_0::{ComponentInfo, StartOfFrameInfo},
     start_of_scan::StartOfScanInfo,
+    stats::timed,
+    JpegError, Result,
 };
-use std::{
-    fmt::Debug,
-    io::{BufRead, BufReader, Read, Result},
-};
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Minimum Coded Unit.
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -24,8 +36,10 @@ pub struct Mcu {
 #[repr(align(32))] // optimize
 pub struct Block(pub [i16; 64]);
 
+/// The values in storage order, 8 in a row, which is zigzag order right after entropy decoding.
+/// See [`Block::debug_zigzag`] for natural order.
 impl Debug for Block {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         for i in 0..8 {
             for j in 0..8 {
                 write!(f, " {}", self.0[i * 8 + j])?;
@@ -36,75 +50,964 @@ impl Debug for Block {
     }
 }
 
-pub struct McuReader<R: Read> {
-    reader: BitReader<R>,
+/// Where to resume decoding a baseline scan, taken by [`McuReader::snapshot`]
+/// at a restart boundary.
+///
+/// The DC predictions and the buffered bits are reset by the RSTn marker there,
+/// so the number of MCUs decoded and the offset in the stream are all to keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DecodeState {
+    /// The number of MCUs decoded, a multiple of the restart interval.
+    pub mcu_index: usize,
+    /// The offset in the stream of the entropy-coded data right after the RSTn marker,
+    /// or the scan for `mcu_index` 0.
+    pub offset: u64,
+}
+
+pub struct McuReader<R: Input> {
+    reader: BitReader<Buffered<R>>,
     sof: StartOfFrameInfo,
-    qts: Vec<QuantizationTable>,
+    /// Quantization tables indexed by id.
+    qts: [Option<QuantizationTable>; 4],
+    /// The quantization table of each component in natural order, for [`Block::idct_dequant`].
+    natural_qts: SmallVec<[[u16; 64]; 4]>,
     huffman_tables: Vec<(HuffmanTree, HuffmanTree)>,
-    last_dc: [i16; 3],
+    last_dc: [i16; 4],
     i: usize,
     total: usize,
     reset_interval: Option<u16>,
+    /// Decoded coefficients of a progressive frame.
+    coefficients: Option<Coefficients>,
+    pub(crate) jfif: Option<Jfif>,
+    /// The payloads of COM segments.
+    pub(crate) comments: Vec<Vec<u8>>,
+    pub(crate) adobe: Option<Adobe>,
+    pub(crate) exif: Option<Exif>,
+    /// The ICC profile reassembled from APP2.
+    pub(crate) icc_profile: Option<Vec<u8>>,
+    /// Whether the stream ended before the whole image is decoded.
+    pub(crate) truncated: bool,
+    /// Whether the final MCU is followed right by EOI.
+    complete: bool,
+    /// Whether to check for EOI after the final MCU, unlike a restart interval decoded alone.
+    expect_eoi: bool,
+    /// The offset of the entropy-coded data of the first scan in the stream.
+    pub(crate) scan_offset: Option<u64>,
+    /// The MCUs before this index are lost in a corrupt restart interval.
+    resync_until: usize,
+    color_space: ColorSpace,
+    upsampling: Upsampling,
+    /// Whether to apply the EXIF orientation to the whole image.
+    auto_orient: bool,
+    /// The stages timed so far, without the bytes read by `reader`.
+    #[cfg(feature = "stats")]
+    pub(crate) stats: DecodeStats,
 }
 
-impl<R: Read> McuReader<R> {
+impl<R: Input> McuReader<R> {
+    /// A reader of all the MCUs of the frame, without tables, metadata or coefficients.
+    fn base(
+        decoder: Buffered<R>,
+        offset: u64,
+        sof: StartOfFrameInfo,
+        qts: [Option<QuantizationTable>; 4],
+    ) -> Self {
+        McuReader {
+            reader: BitReader::new(decoder).with_start(offset),
+            total: sof.mcu_height_num() as usize * sof.mcu_width_num() as usize,
+            reset_interval: None,
+            natural_qts: natural_qts(&sof, &qts),
+            sof,
+            qts,
+            huffman_tables: vec![],
+            last_dc: [0; 4],
+            i: 0,
+            coefficients: None,
+            jfif: None,
+            comments: vec![],
+            adobe: None,
+            exif: None,
+            icc_profile: None,
+            truncated: false,
+            complete: false,
+            expect_eoi: true,
+            scan_offset: None,
+            resync_until: 0,
+            color_space: ColorSpace::default(),
+            upsampling: Upsampling::default(),
+            auto_orient: false,
+            #[cfg(feature = "stats")]
+            stats: DecodeStats::default(),
+        }
+    }
+
     /// Read minimum coded units (MCU).
     pub(super) fn new(
-        decoder: BufReader<R>,
+        decoder: Buffered<R>,
+        offset: u64,
         sof: StartOfFrameInfo,
         sos: StartOfScanInfo,
-        qts: Vec<QuantizationTable>,
-        huffman: Vec<HuffmanTable>,
+        qts: [Option<QuantizationTable>; 4],
+        huffman: HuffmanTables,
         reset_interval: Option<u16>,
     ) -> Result<Self> {
         let mut huffman_tables = Vec::with_capacity(3);
-        for id in sos.table_mapping {
-            let dc = huffman
-                .iter()
-                .find(|h| h.class == id.dc)
-                .ok_or_else(|| error(format!("huffman table not found: {:?}", id.dc)))?;
-            let ac = huffman
-                .iter()
-                .find(|h| h.class == id.ac)
-                .ok_or_else(|| error(format!("huffman table not found: {:?}", id.ac)))?;
-            huffman_tables.push((dc.map.clone(), ac.map.clone()));
+        for id in &sos.table_mapping[..sof.component_number as usize] {
+            let (dc, ac) = (huffman.get(id.dc)?, huffman.get(id.ac)?);
+            huffman_tables.push((dc.clone(), ac.clone()));
         }
+        let base = Self::base(decoder, offset, sof, qts);
+        // a height of 0 is defined by DNL after the scan, until then the total is unknown
+        let total = match base.sof.height {
+            0 => usize::MAX,
+            _ => base.total,
+        };
         Ok(McuReader {
-            reader: BitReader::new(decoder),
-            total: sof.mcu_height_num() as usize * sof.mcu_width_num() as usize,
+            total,
             reset_interval,
-            sof,
-            qts,
             huffman_tables,
-            last_dc: [0; 3],
-            i: 0,
+            ..base
         })
     }
 
+    /// Read MCUs from the coefficients of a progressive frame, which have already been decoded.
+    pub(super) fn with_coefficients(
+        decoder: Buffered<R>,
+        offset: u64,
+        sof: StartOfFrameInfo,
+        qts: [Option<QuantizationTable>; 4],
+        coefficients: Coefficients,
+    ) -> Self {
+        McuReader {
+            coefficients: Some(coefficients),
+            ..Self::base(decoder, offset, sof, qts)
+        }
+    }
+
     /// Read a minimum coded unit (MCU).
+    ///
+    /// If the stream is truncated, the blocks not decoded are filled with zeros,
+    /// and the MCU in which the stream ends is the last one.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<McuRGB>> {
+        let mut rgb = McuRGB::default();
+        Ok(self.next_into(&mut rgb)?.then_some(rgb))
+    }
+
+    /// Read a minimum coded unit (MCU) into `out` like [`Self::next`], reusing its buffer.
+    ///
+    /// Return `false` at the end.
+    fn next_into(&mut self, out: &mut McuRGB) -> Result<bool> {
+        let Some(mcu) = self.next_mcu()? else {
+            return Ok(false);
+        };
+        self.convert_into(&mcu, out);
+        Ok(true)
+    }
+
+    /// Convert the samples of an MCU to RGB.
+    fn convert_into(&mut self, mcu: &Mcu, out: &mut McuRGB) {
+        timed!(
+            self.stats.color_ns,
+            match self.sof.component_number {
+                4 => {
+                    *out = mcu
+                        .to_cmyk(&self.sof, self.transform())
+                        .to_rgb(self.adobe.is_some())
+                }
+                _ => mcu.to_rgb_into(&self.sof, self.color_space, out),
+            }
+        )
+    }
+
+    /// Read a minimum coded unit (MCU) of a CMYK or YCCK image in CMYK.
+    pub fn next_cmyk(&mut self) -> Result<Option<McuCMYK>> {
+        if self.sof.component_number != 4 {
+            return Err(JpegError::InvalidData(format!(
+                "not a CMYK image: {} components",
+                self.sof.component_number
+            )));
+        }
+        let Some(mcu) = self.next_mcu()? else {
+            return Ok(None);
+        };
+        let transform = self.transform();
+        Ok(Some(timed!(
+            self.stats.color_ns,
+            mcu.to_cmyk(&self.sof, transform)
+        )))
+    }
+
+    /// Read a minimum coded unit (MCU) in samples, before the color conversion.
+    fn next_mcu(&mut self) -> Result<Option<Mcu>> {
+        let Some(mut mcu) = self.next_coefficients()? else {
+            return Ok(None);
+        };
+        timed!(
+            self.stats.idct_ns,
+            mcu.itrans_dequant(&self.sof, &self.natural_qts)
+        );
+        Ok(Some(mcu))
+    }
+
+    /// Read a minimum coded unit (MCU) right after the entropy decoding.
+    ///
+    /// The blocks are the quantized coefficients in zigzag order, before [`Mcu::itrans`].
+    pub fn next_coefficients(&mut self) -> Result<Option<Mcu>> {
+        let mcu = timed!(self.stats.huffman_ns, self.read_coefficients()?);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.mcus += mcu.is_some() as u64;
+        }
+        Ok(mcu)
+    }
+
+    /// Entropy-decode the next MCU for [`Self::next_coefficients`].
+    fn read_coefficients(&mut self) -> Result<Option<Mcu>> {
         if self.i == self.total {
             return Ok(None);
         }
+        if let Some(coefficients) = &self.coefficients {
+            self.i += 1;
+            // the scans have been read until EOI
+            self.complete = self.i == self.total && !self.truncated;
+            return Ok(Some(coefficients.mcu(&self.sof, self.i - 1)));
+        }
+        if self.truncated {
+            return Ok(None);
+        }
+        if self.sof.height == 0 {
+            match self.reader.marker_ahead()? {
+                Some(0xDC) => {
+                    self.read_dnl()?;
+                    if self.i >= self.total {
+                        self.check_complete()?;
+                        return Ok(None);
+                    }
+                }
+                Some(0xD0..=0xD7) | None => {}
+                Some(marker) => {
+                    return Err(JpegError::InvalidData(format!(
+                        "DNL not found before marker 0x{marker:02X}"
+                    )))
+                }
+            }
+        }
         self.i += 1;
+        if self.i <= self.resync_until {
+            return Ok(Some(self.zero_mcu()));
+        }
         let mut mcu = Mcu::default();
-        for (id, component) in self.sof.component_infos.clone().iter().enumerate() {
+        let components = self.sof.component_infos.clone();
+        for (id, component) in components.iter().enumerate() {
             for _ in 0..component.vertical_sampling {
                 for _ in 0..component.horizontal_sampling {
-                    let block = self.read_block(id)?;
+                    let block = if self.truncated {
+                        Block([0; 64])
+                    } else {
+                        match self.read_block(id) {
+                            Err(JpegError::Truncated) => {
+                                self.truncated = true;
+                                Block([0; 64])
+                            }
+                            Err(e @ JpegError::InvalidData(_)) => return self.resync(e),
+                            block => block?,
+                        }
+                    };
                     mcu.blocks.push(block);
                 }
             }
         }
-        mcu.itrans(&self.sof, &self.qts);
-        let rgb = mcu.to_rgb(&self.sof);
+        if self.i == self.total && !self.truncated {
+            // no restart marker follows the last interval
+            self.check_complete()?;
+        } else if !self.truncated
+            && matches!(self.reset_interval, Some(r) if self.i.is_multiple_of(r as usize))
+        {
+            match self.reader.reset() {
+                Err(JpegError::Truncated) => self.truncated = true,
+                r => r?,
+            }
+            self.last_dc = [0; 4];
+        }
+        Ok(Some(mcu))
+    }
+
+    /// Whether the whole image has been decoded, with the stream at EOI right after the final MCU.
+    ///
+    /// It is `false` until the final MCU is read, and if the stream is truncated,
+    /// hits EOI early, or has entropy-coded data left after the final MCU.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The offset in the stream at which the entropy-coded data of the first scan begins,
+    /// right after its SOS segment, or `None` if there is no scan.
+    pub fn scan_offset(&self) -> Option<u64> {
+        self.scan_offset
+    }
+
+    /// The number of bytes consumed from the stream so far, from SOI.
+    ///
+    /// After the final MCU of a baseline image, the entropy-coded data spans
+    /// `scan_offset()..bytes_consumed()` with the EOI marker at the end.
+    /// The scans of a progressive image, or non-interleaved ones, are all read
+    /// by [`Decoder::read`] already.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.reader.position()
+    }
+
+    /// The state to resume decoding from by [`Self::resume`], if the next MCU starts
+    /// a restart interval of a baseline scan.
+    ///
+    /// Resuming anywhere else is not safe, since the DC predictions and the bits read ahead
+    /// depend on the data before, so it is `None` in the middle of an interval, without
+    /// restart markers, after the final MCU, or once the stream is truncated or corrupt.
+    pub fn snapshot(&self) -> Option<DecodeState> {
+        let r = self.reset_interval? as usize;
+        let clean = self.coefficients.is_none() && !self.truncated && self.i >= self.resync_until;
+        (clean && self.i.is_multiple_of(r) && self.i < self.total).then_some(DecodeState {
+            mcu_index: self.i,
+            offset: self.reader.position(),
+        })
+    }
+
+    /// Resume decoding from `state` by [`Self::next`] and the like.
+    ///
+    /// The reader must be right at the scan, with its stream going on from `state.offset`,
+    /// e.g. the headers up to [`Self::scan_offset`] and the data from `state.offset`.
+    /// The decoding functions of the whole image can't be used after resuming.
+    pub fn resume(&mut self, state: &DecodeState) -> Result<()> {
+        let r = self.reset_interval.unwrap_or(0) as usize;
+        if self.i != 0
+            || self.coefficients.is_some()
+            || r == 0
+            || !state.mcu_index.is_multiple_of(r)
+            || state.mcu_index >= self.total
+        {
+            return Err(JpegError::InvalidData(format!(
+                "can't resume at MCU {} of {}",
+                state.mcu_index, self.total
+            )));
+        }
+        self.i = state.mcu_index;
+        self.last_dc = [0; 4];
+        // the positions from here on are in the original stream
+        self.reader.start = state.offset.saturating_sub(self.reader.bytes_read);
+        Ok(())
+    }
+
+    /// Check that the expected number of MCUs ends at EOI, for [`Self::is_complete`].
+    ///
+    /// A mismatch is likely a bug in the sizes derived from the sampling factors.
+    fn check_complete(&mut self) -> Result<()> {
+        if !self.expect_eoi {
+            return Ok(());
+        }
+        let marker = self.reader.end_marker()?;
+        self.complete = self.i == self.total && marker == Some(0xD9);
+        #[cfg(debug_assertions)]
+        if !self.complete {
+            tracing::warn!(
+                mcus = self.i,
+                total = self.total,
+                ?marker,
+                "the scan does not end at EOI after the final MCU"
+            );
+        }
+        Ok(())
+    }
+
+    /// Read the DNL segment after the MCUs, which defines the height.
+    fn read_dnl(&mut self) -> Result<()> {
+        let mut buf = [0; 4];
+        self.reader.reader.read_exact(&mut buf)?;
+        let len = u16::from_be_bytes([buf[0], buf[1]]);
+        let height = u16::from_be_bytes([buf[2], buf[3]]);
+        tracing::debug!(len, height, "read section DNL");
+        if len != 4 || height == 0 {
+            return Err(JpegError::InvalidData(format!(
+                "invalid DNL: length {len}, height {height}"
+            )));
+        }
+        self.reader.marker = None;
+        self.reader.count_bytes(buf.len() as u64);
+        self.sof.height = height;
+        self.total = self.sof.mcu_height_num() as usize * self.sof.mcu_width_num() as usize;
+        Ok(())
+    }
+
+    /// Recover from the corrupt data of the current MCU by skipping to the next RSTn marker.
+    ///
+    /// The MCUs until the restart are zero, or `err` is returned if there is no restart.
+    fn resync(&mut self, err: JpegError) -> Result<Option<Mcu>> {
+        let Some(r) = self.reset_interval else {
+            return Err(err);
+        };
+        let Some(n) = self.reader.resync()? else {
+            return Err(err);
+        };
+        // the interval of the current MCU, and the interval after RSTn
+        let r = r as usize;
+        let interval = (self.i - 1) / r;
+        let next = interval + 1 + (n as usize + 8 - interval % 8) % 8;
+        tracing::warn!(mcu = self.i - 1, next = next * r, "corrupt data, resync");
+        self.resync_until = next * r;
+        self.last_dc = [0; 4];
+        Ok(Some(self.zero_mcu()))
+    }
+
+    /// An MCU with all coefficients zero.
+    fn zero_mcu(&self) -> Mcu {
+        Mcu {
+            blocks: smallvec::smallvec![Block([0; 64]); self.sof.blocks_per_mcu()],
+        }
+    }
+
+    /// Decode the whole image.
+    ///
+    /// Return the width, the height and the pixels in row-major order,
+    /// with the padding of edge MCUs cropped.
+    pub fn decode_to_rgb8(self) -> Result<(u32, u32, Vec<RGB>)> {
+        self.decode_to_rgb8_with(&mut McuRGB::default())
+    }
+
+    /// Decode the whole image like [`Self::decode_to_rgb8`], with `mcu` as the buffer of MCUs.
+    pub(crate) fn decode_to_rgb8_with(self, mcu: &mut McuRGB) -> Result<(u32, u32, Vec<RGB>)> {
+        if self.bilinear() {
+            return self.decode_bilinear(None);
+        }
+        self.decode_with(Self::next_into, |p| p, mcu)
+    }
+
+    /// Decode the whole image like [`Self::decode_to_rgb8`], unless `cancel` is set meanwhile.
+    ///
+    /// The flag is polled before each row of MCUs, returning [`JpegError::Cancelled`] once set.
+    pub fn decode_to_rgb8_cancellable(self, cancel: &AtomicBool) -> Result<(u32, u32, Vec<RGB>)> {
+        if self.bilinear() {
+            return self.decode_bilinear(Some(cancel));
+        }
+        let mcu_width_num = self.mcu_width_num() as usize;
+        let mut i = 0;
+        let next = |reader: &mut Self, out: &mut McuRGB| {
+            poll_cancel(Some(cancel), i, mcu_width_num)?;
+            i += 1;
+            reader.next_into(out)
+        };
+        self.decode_with(next, |p| p, &mut McuRGB::default())
+    }
+
+    /// Decode the whole image into `out` like [`Self::decode_to_rgb8`],
+    /// with each row `stride` pixels after the previous one.
+    ///
+    /// The pixels between the rows, and those not decoded in a truncated image, are left as is.
+    /// Nothing is allocated for the image unless the chroma is upsampled bilinearly,
+    /// the height is defined by DNL, or the image is reoriented.
+    pub fn decode_into(mut self, out: &mut [RGB], stride: usize) -> Result<()> {
+        let orientation = self.exif.as_ref().and_then(|exif| exif.orientation);
+        if self.bilinear()
+            || self.sof.height == 0
+            || self.auto_orient && orientation.is_some_and(|o| o != 1)
+        {
+            let (width, height, pixels) = self.decode_to_rgb8()?;
+            check_buffer(out.len(), stride, width, height)?;
+            for (y, row) in pixels.chunks_exact(width as usize).enumerate() {
+                out[y * stride..][..row.len()].copy_from_slice(row);
+            }
+            return Ok(());
+        }
+        check_buffer(out.len(), stride, self.width() as u32, self.height() as u32)?;
+        let mut mcu = McuRGB::default();
+        let mut i = 0;
+        while self.next_into(&mut mcu)? {
+            copy_mcu(&self.sof, out, stride, i, &mcu, &|p| p);
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Decode the whole image like [`Self::decode_to_rgb8`], with the chroma planes
+    /// upsampled bilinearly.
+    fn decode_bilinear(mut self, cancel: Option<&AtomicBool>) -> Result<(u32, u32, Vec<RGB>)> {
+        let matrix = self.color_space.matrix();
+        let planes = self.decode_planes(1, cancel)?;
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let pixels = timed!(self.stats.color_ns, {
+            let sof = &self.sof;
+            let sizes = planes.sizes;
+            let planes = [(0, planes.y), (1, planes.cb), (2, planes.cr)];
+            // the luma too, if a chroma component has higher sampling factors
+            let [y, cb, cr] = planes.map(|(c, plane)| {
+                let info = &sof.component_infos[c];
+                let factor = (
+                    (sof.max_horizontal_sampling / info.horizontal_sampling) as usize,
+                    (sof.max_vertical_sampling / info.vertical_sampling) as usize,
+                );
+                if factor == (1, 1) {
+                    return plane;
+                }
+                let size = (sizes[c].0 as usize, sizes[c].1 as usize);
+                upsample_bilinear(&plane, size, factor, (width, height))
+            });
+            (y.into_iter().zip(cb).zip(cr))
+                .map(|((y, cb), cr)| matrix.rgb(y, cb, cr))
+                .collect()
+        });
+        Ok(self.orient(width as u32, height as u32, pixels))
+    }
+
+    /// Whether the subsampled components are upsampled by [`Upsampling::Bilinear`]
+    /// in the whole image.
+    fn bilinear(&self) -> bool {
+        let sof = &self.sof;
+        self.upsampling == Upsampling::Bilinear
+            && sof.component_number == 3
+            && sof.height != 0
+            && sof.component_infos.iter().any(|c| {
+                c.sampling_factor() != (sof.max_horizontal_sampling, sof.max_vertical_sampling)
+            })
+    }
 
-        if matches!(self.reset_interval, Some(r) if self.i % r as usize == 0) {
-            self.reader.reset()?;
-            self.last_dc = [0; 3];
+    /// Decode the whole image in RGBA, with alpha set to 255.
+    ///
+    /// Return the width, the height and `width * height * 4` bytes,
+    /// with rows tightly packed, like [`Self::decode_to_rgb8`].
+    pub fn decode_rgba8(self) -> Result<(u32, u32, Vec<u8>)> {
+        self.decode_as(PixelFormat::Rgba)
+    }
+
+    /// Decode the whole image in the bytes of `format`.
+    ///
+    /// Return the width, the height and `width * height * format.bytes_per_pixel()` bytes,
+    /// with rows tightly packed, like [`Self::decode_to_rgb8`].
+    pub fn decode_as(self, format: PixelFormat) -> Result<(u32, u32, Vec<u8>)> {
+        // branch once here, so that each layout has its own copy loop
+        match format {
+            PixelFormat::Rgb => self.decode_bytes(|p| [p.r, p.g, p.b]),
+            PixelFormat::Bgr => self.decode_bytes(|p| [p.b, p.g, p.r]),
+            PixelFormat::Rgba => self.decode_bytes(|p| [p.r, p.g, p.b, 255]),
+            PixelFormat::Bgra => self.decode_bytes(|p| [p.b, p.g, p.r, 255]),
         }
-        Ok(Some(rgb))
+    }
+
+    /// Decode the whole image with each pixel converted to `N` bytes by `convert`.
+    fn decode_bytes<const N: usize>(
+        self,
+        convert: impl Fn(RGB) -> [u8; N],
+    ) -> Result<(u32, u32, Vec<u8>)>
+    where
+        [u8; N]: Default,
+    {
+        let (width, height, pixels) = if self.bilinear() {
+            let (width, height, pixels) = self.decode_to_rgb8()?;
+            (
+                width,
+                height,
+                pixels.into_iter().map(convert).collect::<Vec<_>>(),
+            )
+        } else {
+            self.decode_with(Self::next_into, convert, &mut McuRGB::default())?
+        };
+        Ok((width, height, pixels.into_flattened()))
+    }
+
+    /// Decode the whole image in grayscale from the luma only, like [`Self::decode_to_rgb8`].
+    ///
+    /// The chroma blocks are entropy-decoded, but neither transformed nor upsampled.
+    pub fn decode_luma8(self) -> Result<(u32, u32, Vec<u8>)> {
+        if self.sof.component_number == 4 {
+            return Err(JpegError::InvalidData("no luma in a CMYK image".into()));
+        }
+        let next = |reader: &mut Self, out: &mut McuPixels<u8>| {
+            let Some(mut mcu) = reader.next_coefficients()? else {
+                return Ok(false);
+            };
+            timed!(
+                reader.stats.idct_ns,
+                mcu.itrans_dequant(&reader.sof, &reader.natural_qts[..1])
+            );
+            timed!(reader.stats.color_ns, mcu.to_luma_into(&reader.sof, out));
+            Ok(true)
+        };
+        self.decode_with(next, |p| p, &mut McuPixels::default())
+    }
+
+    /// Decode the whole image into the planes of components, neither upsampled nor converted.
+    ///
+    /// A plane of a component sampled `h`x`v` is `ceil(width * h / max_h)` by
+    /// `ceil(height * v / max_v)`.
+    pub fn decode_planar(mut self) -> Result<PlanarImage> {
+        self.decode_planes(1, None)
+    }
+
+    /// Decode a 4:2:0 image in I420: the Y plane, then the Cb and the Cr planes at half
+    /// resolution, neither upsampled nor converted.
+    ///
+    /// Return the width, the height of the Y plane and the planes in a row.
+    /// Other sampling factors and grayscale are an error.
+    pub fn decode_i420(self) -> Result<(u32, u32, Vec<u8>)> {
+        let sof = &self.sof;
+        let half = (sof.max_horizontal_sampling, sof.max_vertical_sampling);
+        if sof.component_number != 3
+            || (sof.component_infos[1..].iter())
+                .any(|c| (2 * c.horizontal_sampling, 2 * c.vertical_sampling) != half)
+        {
+            let factors: Vec<_> = (sof.component_infos.iter())
+                .map(|c| c.sampling_factor())
+                .collect();
+            return Err(JpegError::InvalidData(format!(
+                "not 4:2:0 but sampled {factors:?}"
+            )));
+        }
+        let planar = self.decode_planar()?;
+        let (width, height) = planar.sizes[0];
+        let mut out = planar.y;
+        out.extend(planar.cb);
+        out.extend(planar.cr);
+        Ok((width, height, out))
+    }
+
+    /// Decode the whole image scaled down by `denom` in 1, 2, 4, 8, like [`Self::decode_to_rgb8`].
+    ///
+    /// Each block is transformed from its top-left `8 / denom` square of coefficients,
+    /// which is much faster than decoding the whole image and resizing.
+    /// The chroma is upsampled by the nearest sample.
+    pub fn decode_scaled(mut self, denom: u8) -> Result<(u32, u32, Vec<RGB>)> {
+        if !matches!(denom, 1 | 2 | 4 | 8) {
+            return Err(JpegError::InvalidData(format!("invalid scale: 1/{denom}")));
+        }
+        let matrix = self.color_space.matrix();
+        let planes = self.decode_planes(denom as usize, None)?;
+        let sizes = planes.sizes;
+        if self.sof.component_number == 1 {
+            let pixels = planes.y.iter().map(|&y| matrix.gray(y)).collect();
+            return Ok((sizes[0].0, sizes[0].1, pixels));
+        }
+        let sof = &self.sof;
+        let width = (sof.width as usize).div_ceil(denom as usize);
+        let height = (sof.height as usize).div_ceil(denom as usize);
+        // the luma is subsampled too if a chroma component has higher sampling factors
+        let [fh0, fh1, fh2] = [0, 1, 2].map(|c| {
+            (sof.max_horizontal_sampling / sof.component_infos[c].horizontal_sampling) as usize
+        });
+        let [fv0, fv1, fv2] = [0, 1, 2].map(|c| {
+            (sof.max_vertical_sampling / sof.component_infos[c].vertical_sampling) as usize
+        });
+        let [w0, w1, w2] = sizes.map(|(w, _)| w as usize);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let luma = planes.y[y / fv0 * w0 + x / fh0];
+                let cb = planes.cb[y / fv1 * w1 + x / fh1];
+                let cr = planes.cr[y / fv2 * w2 + x / fh2];
+                pixels.push(matrix.rgb(luma, cb, cr));
+            }
+        }
+        Ok((width as u32, height as u32, pixels))
+    }
+
+    /// Decode a preview scaled down by 8 from the DC coefficients only, one pixel per block,
+    /// like [`Self::decode_scaled`] of 8.
+    ///
+    /// The AC coefficients are still entropy-decoded, but never dequantized nor transformed.
+    pub fn decode_dc_preview(self) -> Result<(u32, u32, Vec<RGB>)> {
+        self.decode_scaled(8)
+    }
+
+    /// Decode the samples of each component into planes scaled down by `denom`.
+    fn decode_planes(&mut self, denom: usize, cancel: Option<&AtomicBool>) -> Result<PlanarImage> {
+        let n = self.sof.component_number as usize;
+        if n == 4 || self.sof.height == 0 {
+            return Err(JpegError::InvalidData(format!(
+                "not supported in planes: {n} components, height {}",
+                self.sof.height
+            )));
+        }
+        let sof = self.sof.clone();
+        let (max_h, max_v) = (sof.max_horizontal_sampling, sof.max_vertical_sampling);
+        let sizes: [(u32, u32); 3] = core::array::from_fn(|c| {
+            if c >= n {
+                return (0, 0);
+            }
+            let info = &sof.component_infos[c];
+            let (h, v) = (
+                info.horizontal_sampling as u32,
+                info.vertical_sampling as u32,
+            );
+            (
+                (sof.width as u32 * h).div_ceil(max_h as u32 * denom as u32),
+                (sof.height as u32 * v).div_ceil(max_v as u32 * denom as u32),
+            )
+        });
+        let mut planes = sizes.map(|(w, h)| vec![0; w as usize * h as usize]);
+        // the size of a scaled block
+        let size = 8 / denom;
+        let mcu_width_num = sof.mcu_width_num() as usize;
+        let mut i = 0;
+        loop {
+            poll_cancel(cancel, i, mcu_width_num)?;
+            let Some(mcu) = self.next_coefficients()? else {
+                break;
+            };
+            timed!(self.stats.idct_ns, {
+                let mut blocks = mcu.blocks.iter();
+                for (c, plane) in planes[..n].iter_mut().enumerate() {
+                    let info = &sof.component_infos[c];
+                    let qt = &self.qts[info.quant_table_id as usize]
+                        .as_ref()
+                        .expect("quantization table not defined")
+                        .values;
+                    let (ch, cv) = (
+                        info.horizontal_sampling as usize,
+                        info.vertical_sampling as usize,
+                    );
+                    let (width, height) = (sizes[c].0 as usize, sizes[c].1 as usize);
+                    for v in 0..cv {
+                        for h in 0..ch {
+                            let block = blocks.next().unwrap();
+                            let block = match size {
+                                8 => block.idct_dequant(&self.natural_qts[c]),
+                                1 => {
+                                    let mut dc = Block([0; 64]);
+                                    dc.0[0] = block.dc_mean(qt[0]);
+                                    dc
+                                }
+                                _ => block.dequantize_zigzag(qt).idct_scaled(size),
+                            };
+                            let x0 = (i % mcu_width_num * ch + h) * size;
+                            let y0 = (i / mcu_width_num * cv + v) * size;
+                            for y in y0..(y0 + size).min(height) {
+                                for x in x0..(x0 + size).min(width) {
+                                    plane[y * width + x] = to_u8(block.0[(y - y0) * 8 + x - x0]);
+                                }
+                            }
+                        }
+                    }
+                }
+            });
+            i += 1;
+        }
+        let [y, cb, cr] = planes;
+        Ok(PlanarImage { y, cb, cr, sizes })
+    }
+
+    /// Decode the whole CMYK or YCCK image in CMYK, like [`Self::decode_to_rgb8`].
+    pub fn decode_to_cmyk8(self) -> Result<(u32, u32, Vec<CMYK>)> {
+        let next = |reader: &mut Self, out: &mut McuCMYK| {
+            let cmyk = reader.next_cmyk()?;
+            Ok(cmyk.map(|cmyk| *out = cmyk).is_some())
+        };
+        self.decode_with(next, |p| p, &mut McuPixels::default())
+    }
+
+    /// Decode the whole image row by row, calling `f` with the index and the `width` pixels
+    /// of each row.
+    ///
+    /// The rows are passed in order from top to bottom, and only one row of MCUs is buffered.
+    /// Like [`Self::decode_to_rgb8`], the rows missing in a truncated stream are black.
+    pub fn for_each_row(mut self, mut f: impl FnMut(usize, &[RGB])) -> Result<()> {
+        if self.sof.height == 0 || self.bilinear() {
+            // the rows are unknown until DNL, or need the next row of MCUs to be upsampled
+            let (width, _, pixels) = self.decode_to_rgb8()?;
+            for (y, row) in pixels.chunks_exact(width as usize).enumerate() {
+                f(y, row);
+            }
+            return Ok(());
+        }
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let mcu_height = self.mcu_height() as usize;
+        let mut rows = vec![RGB::default(); width * mcu_height];
+        let mut mcu = McuRGB::default();
+        for y0 in (0..height).step_by(mcu_height) {
+            rows.fill(RGB::default());
+            for i in 0..self.mcu_width_num() as usize {
+                if !self.next_into(&mut mcu)? {
+                    break;
+                }
+                copy_mcu(&self.sof, &mut rows, width, i, &mcu, &|p| p);
+            }
+            for h in 0..mcu_height.min(height - y0) {
+                f(y0 + h, &rows[h * width..(h + 1) * width]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the whole image like [`Self::decode_to_rgb8`], with restart intervals in parallel.
+    ///
+    /// Each interval is independent, so the entropy-coded data is split on RSTn markers and
+    /// decoded on the rayon thread pool. Fall back to the sequential decoding without DRI.
+    /// The image is reoriented by [`Self::with_auto_orient`] either way.
+    #[cfg(feature = "std")]
+    pub fn decode_parallel(mut self) -> Result<Vec<RGB>> {
+        use std::io::Read;
+
+        let interval = match self.reset_interval {
+            Some(r)
+                if self.coefficients.is_none()
+                    && self.i == 0
+                    && self.sof.height != 0
+                    && !self.bilinear() =>
+            {
+                r as usize
+            }
+            _ => return Ok(self.decode_to_rgb8()?.2),
+        };
+        let start = self.reader.position();
+        let mut data = vec![];
+        self.reader.reader.read_to_end(&mut data)?;
+        let segments = split_restarts(&data);
+
+        let (sof, qts, huffman_tables) = (&self.sof, &self.qts, &self.huffman_tables);
+        let (adobe, total, color_space) = (self.adobe, self.total, self.color_space);
+        let mcus = segments
+            .par_iter()
+            .take(total.div_ceil(interval))
+            .enumerate()
+            .map(|(k, segment)| {
+                // the offset of the segment in the stream, for the errors
+                let offset = start + (segment.as_ptr() as usize - data.as_ptr() as usize) as u64;
+                let base = McuReader::<crate::source::Slice>::base(
+                    *segment,
+                    offset,
+                    sof.clone(),
+                    qts.clone(),
+                );
+                let mut reader = McuReader {
+                    huffman_tables: huffman_tables.clone(),
+                    total: interval.min(total - k * interval),
+                    adobe,
+                    color_space,
+                    expect_eoi: false,
+                    ..base
+                };
+                let mut mcus = Vec::with_capacity(reader.total);
+                loop {
+                    match reader.next() {
+                        Ok(Some(mcu)) => mcus.push(mcu),
+                        Ok(None) => break,
+                        // the rest of the interval is lost, like `Self::resync`
+                        Err(JpegError::InvalidData(e)) => {
+                            let mcu = k * interval + mcus.len();
+                            tracing::warn!(mcu, error = e, "corrupt data, resync");
+                            let mut zero = McuRGB::default();
+                            reader.convert_into(&reader.zero_mcu(), &mut zero);
+                            mcus.resize(reader.total, zero);
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(mcus)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let mut pixels = vec![RGB::default(); width * height];
+        // by the index of the interval, in case of one cut short
+        for (k, mcus) in mcus.iter().enumerate() {
+            for (j, mcu) in mcus.iter().enumerate() {
+                copy_mcu(&self.sof, &mut pixels, width, k * interval + j, mcu, &|p| p);
+            }
+        }
+        Ok(self.orient(width as u32, height as u32, pixels).2)
+    }
+
+    /// Decode the whole image by `next` MCU, converting each pixel by `convert`.
+    fn decode_with<P: Copy + Default + From<u8>, Q: Copy + Default>(
+        mut self,
+        mut next: impl FnMut(&mut Self, &mut McuPixels<P>) -> Result<bool>,
+        convert: impl Fn(P) -> Q,
+        // reused for every MCU
+        mcu: &mut McuPixels<P>,
+    ) -> Result<(u32, u32, Vec<Q>)> {
+        let width = self.width();
+        let mut pixels = vec![Q::default(); width as usize * self.height() as usize];
+        // the MCUs before the height is defined by DNL
+        let mut pending = vec![];
+        let mut i = 0;
+        while next(&mut self, mcu)? {
+            if self.sof.height == 0 {
+                pending.push(mcu.clone());
+            } else {
+                copy_mcu(&self.sof, &mut pixels, width as usize, i, mcu, &convert);
+            }
+            i += 1;
+        }
+        let height = self.height();
+        if !pending.is_empty() && height != 0 {
+            pixels = vec![Q::default(); width as usize * height as usize];
+            for (i, mcu) in pending.iter().enumerate().take(self.total) {
+                copy_mcu(&self.sof, &mut pixels, width as usize, i, mcu, &convert);
+            }
+        }
+        Ok(self.orient(width as u32, height as u32, pixels))
+    }
+
+    /// Apply the EXIF orientation to the whole image if [`Self::with_auto_orient`].
+    fn orient<P: Copy>(&self, width: u32, height: u32, pixels: Vec<P>) -> (u32, u32, Vec<P>) {
+        match self.exif.as_ref().and_then(|exif| exif.orientation) {
+            Some(orientation) if self.auto_orient => {
+                apply_orientation(width, height, pixels, orientation)
+            }
+            _ => (width, height, pixels),
+        }
+    }
+
+    /// Decode the `w * h` pixels at column `x` and row `y` in row-major order.
+    ///
+    /// The whole stream up to the region is still entropy-decoded for the DC prediction,
+    /// but only the MCUs intersecting the region are transformed and converted.
+    /// The region is in the orientation after [`Self::with_auto_orient`].
+    pub fn decode_region(mut self, x: u32, y: u32, w: u32, h: u32) -> Result<Vec<RGB>> {
+        let orientation = self.exif.as_ref().and_then(|exif| exif.orientation);
+        if self.sof.height == 0 || self.auto_orient && orientation.is_some_and(|o| o != 1) {
+            // the dimensions are unknown until DNL, or the MCUs are reoriented
+            let (width, height, pixels) = self.decode_to_rgb8()?;
+            check_region(width, height, x, y, w, h)?;
+            let mut region = Vec::with_capacity(w as usize * h as usize);
+            for row in pixels
+                .chunks_exact(width as usize)
+                .skip(y as usize)
+                .take(h as usize)
+            {
+                region.extend_from_slice(&row[x as usize..(x + w) as usize]);
+            }
+            return Ok(region);
+        }
+        check_region(self.width() as u32, self.height() as u32, x, y, w, h)?;
+        let (x, y, w, h) = (x as usize, y as usize, w as usize, h as usize);
+        let (mcu_width, mcu_height) = (self.sof.mcu_width() as usize, self.mcu_height() as usize);
+        let mcu_width_num = self.mcu_width_num() as usize;
+        let mut region = vec![RGB::default(); w * h];
+        let mut rgb = McuRGB::default();
+        for i in 0.. {
+            let (x0, y0) = (
+                i % mcu_width_num * mcu_width,
+                i / mcu_width_num * mcu_height,
+            );
+            if y0 >= y + h {
+                break;
+            }
+            let Some(mut mcu) = self.next_coefficients()? else {
+                break;
+            };
+            let (xs, ys) = (
+                x.max(x0)..(x + w).min(x0 + mcu_width),
+                y.max(y0)..(y + h).min(y0 + mcu_height),
+            );
+            if xs.is_empty() || ys.is_empty() {
+                continue;
+            }
+            mcu.itrans_dequant(&self.sof, &self.natural_qts);
+            self.convert_into(&mcu, &mut rgb);
+            for py in ys {
+                for px in xs.clone() {
+                    region[(py - y) * w + px - x] = rgb.pixel(px - x0, py - y0);
+                }
+            }
+        }
+        Ok(region)
     }
 
     pub fn width(&self) -> u16 {
@@ -115,10 +1018,116 @@ impl<R: Read> McuReader<R> {
         self.sof.height
     }
 
+    /// The number of components: 1 for grayscale, 3 for color, 4 for CMYK or YCCK.
+    pub fn component_number(&self) -> u8 {
+        self.sof.component_number
+    }
+
+    /// The components in the order of the frame header, with their ids as read.
+    pub fn component_infos(&self) -> &[ComponentInfo] {
+        &self.sof.component_infos
+    }
+
+    /// Whether the stream ended before the whole image is decoded.
+    ///
+    /// A truncated baseline image stops early, while a truncated progressive image
+    /// has all MCUs but with the coefficients of the missing scans.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The Adobe header, if the file has one.
+    pub fn adobe(&self) -> Option<&Adobe> {
+        self.adobe.as_ref()
+    }
+
+    /// The color transform of the components, from the Adobe header.
+    pub fn transform(&self) -> ColorTransform {
+        self.adobe.map(|a| a.transform).unwrap_or_default()
+    }
+
+    /// The payloads of COM segments, usually text.
+    pub fn comments(&self) -> &[Vec<u8>] {
+        &self.comments
+    }
+
+    /// The quantization tables indexed by id,
+    /// to dequantize the blocks of [`Self::next_coefficients`].
+    pub fn quantization_tables(&self) -> &[Option<QuantizationTable>; 4] {
+        &self.qts
+    }
+
+    /// Convert YCbCr to RGB in `color_space`, the JFIF one by default.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        self.color_space = color_space;
+    }
+
+    /// Upsample the subsampled chroma by `upsampling`, the nearest neighbor by default.
+    ///
+    /// Only the methods decoding the whole image are affected, like [`Self::decode_to_rgb8`]
+    /// and [`Self::for_each_row`], while MCUs, regions and scaled images are left as is.
+    /// [`Upsampling::Bilinear`] applies to YCbCr images with a height defined in SOF.
+    pub fn with_upsampling(mut self, upsampling: Upsampling) -> Self {
+        self.upsampling = upsampling;
+        self
+    }
+
+    /// Transform the whole image to be upright by the EXIF orientation, off by default.
+    ///
+    /// The width and the height are swapped for the rotations of 90 and 270 degrees.
+    /// Only the methods returning the width and the height are affected, like
+    /// [`Self::decode_to_rgb8`], while MCUs and rows are left as is.
+    pub fn with_auto_orient(mut self, auto_orient: bool) -> Self {
+        self.auto_orient = auto_orient;
+        self
+    }
+
+    /// The time spent in each stage and the amount of data decoded so far.
+    ///
+    /// The MCUs decoded by [`Self::decode_parallel`] on other threads are not counted.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DecodeStats {
+        DecodeStats {
+            bytes_read: self.stats.bytes_read + self.reader.bytes_read,
+            ..self.stats
+        }
+    }
+
+    /// The EXIF metadata, if the file has one.
+    pub fn exif(&self) -> Option<&Exif> {
+        self.exif.as_ref()
+    }
+
+    /// The ICC profile, if the file has a complete one.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_deref()
+    }
+
+    /// The JFIF header, if the file has one.
+    pub fn jfif(&self) -> Option<&Jfif> {
+        self.jfif.as_ref()
+    }
+
+    /// The number of MCUs read, and the total number `mcu_width_num * mcu_height_num`.
+    ///
+    /// The total is `usize::MAX` until the height is defined by DNL.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.i, self.total)
+    }
+
+    /// The index of the next MCU in raster order, i.e. the number of MCUs read.
+    pub fn mcu_index(&self) -> usize {
+        self.i
+    }
+
     pub fn mcu_width_num(&self) -> u16 {
         self.sof.mcu_width_num()
     }
 
+    pub fn mcu_height_num(&self) -> u16 {
+        self.sof.mcu_height_num()
+    }
+
     pub fn mcu_height(&self) -> u16 {
         self.sof.mcu_height()
     }
@@ -135,6 +1144,12 @@ impl<R: Read> McuReader<R> {
                 0xF0 => i += 16,
                 code => {
                     let zeros = (code >> 4) as usize;
+                    if i + zeros >= 64 {
+                        return Err(JpegError::InvalidData(format!(
+                            "AC coefficient out of block at byte 0x{:X}",
+                            self.reader.position()
+                        )));
+                    }
                     let value = self.reader.read_value(code & 0x0F)?;
                     x[i + zeros] = value;
                     i += zeros + 1;
@@ -149,48 +1164,337 @@ impl<R: Read> McuReader<R> {
         let (map, _) = &self.huffman_tables[id];
         let dc = &mut self.last_dc[id];
         let len = self.reader.read_decode_haffman(map)?;
-        *dc += self.reader.read_value(len)?;
+        *dc = dc.wrapping_add(self.reader.read_value(len)?);
         Ok(*dc)
     }
 }
 
-pub struct BitReader<R: Read> {
-    reader: BufReader<R>,
+/// The decoded RGB bytes in raster order as a [`Read`], with one row of MCUs buffered.
+///
+/// The whole image is buffered instead if the height is defined by DNL, it is
+/// reoriented by [`McuReader::with_auto_orient`], or the chroma is upsampled bilinearly.
+#[cfg(feature = "std")]
+pub struct PixelReader<R: Input> {
+    reader: Option<McuReader<R>>,
+    /// The pixels of the current row of MCUs.
+    rows: Vec<RGB>,
+    /// The range of unread bytes in `rows`.
+    pos: usize,
+    len: usize,
+    /// The first row of the next row of MCUs.
+    y0: usize,
+    mcu: McuRGB,
+}
+
+#[cfg(feature = "std")]
+impl<R: Input> PixelReader<R> {
+    pub fn new(reader: McuReader<R>) -> Result<Self> {
+        let orientation = reader.exif.as_ref().and_then(|exif| exif.orientation);
+        if reader.sof.height == 0
+            || reader.bilinear()
+            || reader.auto_orient && orientation.is_some_and(|o| o != 1)
+        {
+            let (_, _, rows) = reader.decode_to_rgb8()?;
+            return Ok(PixelReader {
+                reader: None,
+                pos: 0,
+                len: rows.len() * 3,
+                rows,
+                y0: 0,
+                mcu: McuRGB::default(),
+            });
+        }
+        let rows = vec![RGB::default(); reader.width() as usize * reader.mcu_height() as usize];
+        Ok(PixelReader {
+            reader: Some(reader),
+            rows,
+            pos: 0,
+            len: 0,
+            y0: 0,
+            mcu: McuRGB::default(),
+        })
+    }
+
+    /// Decode the next row of MCUs, returning `false` at the end of the image.
+    fn fill(&mut self) -> Result<bool> {
+        let Some(reader) = &mut self.reader else {
+            return Ok(false);
+        };
+        let (width, height) = (reader.width() as usize, reader.height() as usize);
+        if self.y0 >= height {
+            return Ok(false);
+        }
+        let mcu_height = reader.mcu_height() as usize;
+        self.rows.fill(RGB::default());
+        for i in 0..reader.mcu_width_num() as usize {
+            if !reader.next_into(&mut self.mcu)? {
+                break;
+            }
+            copy_mcu(&reader.sof, &mut self.rows, width, i, &self.mcu, &|p| p);
+        }
+        self.pos = 0;
+        self.len = mcu_height.min(height - self.y0) * width * 3;
+        self.y0 += mcu_height;
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Input> std::io::Read for PixelReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.len {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let bytes = &RGB::as_bytes(&self.rows)[self.pos..self.len];
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// The quantization table of each component in natural order.
+fn natural_qts(
+    sof: &StartOfFrameInfo,
+    qts: &[Option<QuantizationTable>; 4],
+) -> SmallVec<[[u16; 64]; 4]> {
+    (sof.component_infos.iter())
+        .map(|c| {
+            qts[c.quant_table_id as usize]
+                .as_ref()
+                .expect("quantization table not defined")
+                .in_natural_order()
+        })
+        .collect()
+}
+
+/// Check that the `w * h` region at (`x`, `y`) is in the `width * height` image.
+fn check_region(width: u32, height: u32, x: u32, y: u32, w: u32, h: u32) -> Result<()> {
+    let fits = |start: u32, len: u32, size: u32| start.checked_add(len).is_some_and(|e| e <= size);
+    if !fits(x, w, width) || !fits(y, h, height) {
+        return Err(JpegError::InvalidData(format!(
+            "region {w}x{h} at ({x}, {y}) out of the {width}x{height} image"
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `len` pixels hold `height` rows of `width` pixels, `stride` apart.
+fn check_buffer(len: usize, stride: usize, width: u32, height: u32) -> Result<()> {
+    let (width, height) = (width as usize, height as usize);
+    let needed = match height {
+        0 => Some(0),
+        _ => (stride.checked_mul(height - 1)).and_then(|n| n.checked_add(width)),
+    };
+    if stride < width || needed.is_none_or(|needed| len < needed) {
+        return Err(JpegError::InvalidData(format!(
+            "buffer of {len} pixels too small for {width}x{height} with stride {stride}"
+        )));
+    }
+    Ok(())
+}
+
+/// Fail if `cancel` is set, before the `i`-th MCU if it starts a row.
+fn poll_cancel(cancel: Option<&AtomicBool>, i: usize, mcu_width_num: usize) -> Result<()> {
+    match cancel {
+        Some(cancel) if i.is_multiple_of(mcu_width_num) && cancel.load(Ordering::Relaxed) => {
+            Err(JpegError::Cancelled)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copy the `i`-th MCU into the `pixels` of the image with rows `stride` apart,
+/// with the padding cropped.
+fn copy_mcu<P: Copy + From<u8>, Q>(
+    sof: &StartOfFrameInfo,
+    pixels: &mut [Q],
+    stride: usize,
+    i: usize,
+    mcu: &McuPixels<P>,
+    convert: &impl Fn(P) -> Q,
+) {
+    let (width, height) = (sof.width as usize, sof.height as usize);
+    let (mcu_width, mcu_height) = (sof.mcu_width() as usize, sof.mcu_height() as usize);
+    let mcu_width_num = sof.mcu_width_num() as usize;
+    let (x0, y0) = (
+        i % mcu_width_num * mcu_width,
+        i / mcu_width_num * mcu_height,
+    );
+    let len = mcu_width.min(width - x0);
+    for h in 0..mcu_height.min(height - y0) {
+        let row = &mut pixels[(y0 + h) * stride + x0..][..len];
+        for (q, p) in row.iter_mut().zip(mcu.line(h)) {
+            *q = convert(p);
+        }
+    }
+}
+
+/// Split the entropy-coded data on RSTn markers, until any other marker.
+#[cfg(feature = "std")]
+fn split_restarts(data: &[u8]) -> Vec<&[u8]> {
+    let mut segments = vec![];
+    let (mut start, mut i) = (0, 0);
+    while i + 1 < data.len() {
+        match (data[i], data[i + 1]) {
+            (0xFF, 0xD0..=0xD7) => {
+                segments.push(&data[start..i]);
+                start = i + 2;
+                i += 2;
+            }
+            (0xFF, 0x00) => i += 2,
+            (0xFF, _) => break,
+            _ => i += 1,
+        }
+    }
+    if i + 1 >= data.len() {
+        i = data.len();
+    }
+    segments.push(&data[start..i]);
+    segments
+}
+
+impl<R: Input> Iterator for McuReader<R> {
+    type Item = Result<McuRGB>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        McuReader::next(self).transpose()
+    }
+}
+
+pub struct BitReader<R: ByteSource> {
+    reader: R,
     buf: u32,
     /// The lower `count` bits of `buf` is valid.
     count: u8,
+    /// The marker which terminates the entropy-coded segment, once reached.
+    marker: Option<u8>,
+    /// The number of zero bits appended to `buf` after the end of stream.
+    padding: u8,
+    /// The number of zero bits appended to `buf` after `marker`.
+    appended: u32,
+    /// The number of bytes read from `reader`.
+    pub(crate) bytes_read: u64,
+    /// The position of `reader` in the stream.
+    start: u64,
 }
 
-impl<R: Read> BitReader<R> {
-    pub fn new(reader: BufReader<R>) -> Self {
+impl<R: ByteSource> BitReader<R> {
+    pub fn new(reader: R) -> Self {
         Self {
             reader,
             buf: 0,
             count: 0,
+            marker: None,
+            padding: 0,
+            appended: 0,
+            bytes_read: 0,
+            start: 0,
         }
     }
 
+    /// Start counting [`Self::position`] from `start`, the position of the reader in the stream.
+    pub(crate) fn with_start(mut self, start: u64) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// The position in the stream after the bytes read so far.
+    ///
+    /// It is only approximate in the middle of the data, which is read up to 2 bytes ahead.
+    pub fn position(&self) -> u64 {
+        self.start + self.bytes_read
+    }
+
+    /// The marker which has been read past the end of the entropy-coded segment.
+    pub(crate) fn marker(&self) -> Option<u8> {
+        self.marker
+    }
+
     /// Clear buffer and consume the next marker.
-    fn reset(&mut self) -> Result<()> {
-        if self.count < 8 {
+    pub(crate) fn reset(&mut self) -> Result<()> {
+        if self.marker.take().is_none() {
             // marker not peeked
             let mut buf = [0; 2];
             self.reader.read_exact(&mut buf)?;
-            assert_eq!(buf[0], 0xFF);
-        } else {
-            // marker peeked
-            debug_assert_eq!(self.count, 16);
+            self.count_bytes(2);
+            if buf[0] != 0xFF {
+                return Err(JpegError::InvalidData(format!(
+                    "expected RSTn, found 0x{:02X}{:02X} at byte 0x{:X}",
+                    buf[0],
+                    buf[1],
+                    self.position() - 2
+                )));
+            }
         }
         self.buf = 0;
         self.count = 0;
+        self.appended = 0;
         Ok(())
     }
 
+    /// The marker right after the bits read so far, if only the padding bits are left.
+    pub(crate) fn marker_ahead(&mut self) -> Result<Option<u8>> {
+        if self.marker.is_none() && self.count < 8 {
+            let buf = self.reader.fill()?;
+            if buf.len() >= 2 && buf[0] == 0xFF && buf[1] != 0x00 && buf[1] != 0xFF {
+                self.marker = Some(buf[1]);
+                self.reader.consume(2);
+                self.count_bytes(2);
+            }
+        }
+        Ok(self.marker)
+    }
+
+    /// The marker which ends the entropy-coded segment, if all the data before it has been read
+    /// and none of the bits appended after it, i.e. only the fill bits of the last byte are left.
+    pub(crate) fn end_marker(&mut self) -> Result<Option<u8>> {
+        match (self.count as u32).checked_sub(self.appended) {
+            Some(left) if left < 8 => self.marker_ahead(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Skip to the next RSTn marker, returning n,
+    /// or `None` at any other marker or the end of stream.
+    pub(crate) fn resync(&mut self) -> Result<Option<u8>> {
+        self.buf = 0;
+        self.count = 0;
+        self.padding = 0;
+        self.appended = 0;
+        let mut marker = self.marker.take();
+        let mut prefix = false;
+        while marker.is_none() {
+            let Some(b) = self.read_byte()? else {
+                return Ok(None);
+            };
+            match (prefix, b) {
+                (_, 0xFF) => prefix = true,
+                (true, 0x00) | (false, _) => prefix = false,
+                (true, c) => marker = Some(c),
+            }
+        }
+        match marker {
+            Some(c @ 0xD0..=0xD7) => Ok(Some(c - 0xD0)),
+            _ => {
+                self.marker = marker;
+                Ok(None)
+            }
+        }
+    }
+
     pub fn read_decode_haffman(&mut self, map: &HuffmanTree) -> Result<u8> {
         let x = self.peek_16()?;
         let (len, val) = map.get(x);
-        debug_assert_ne!(len, 0);
-        self.consume(len);
+        if len == 0 {
+            return Err(JpegError::InvalidData(format!(
+                "invalid huffman code {x:016b} at byte 0x{:X}",
+                self.position()
+            )));
+        }
+        self.consume(len)?;
         // tracing::debug!("haffman: {len} {val}");
         Ok(val)
     }
@@ -200,31 +1504,49 @@ impl<R: Read> BitReader<R> {
         if len == 0 {
             return Ok(0);
         }
-        let mut v = self.peek(len)? as i16;
+        if len > 15 {
+            return Err(JpegError::InvalidData(format!(
+                "invalid value length {len} at byte 0x{:X}",
+                self.position()
+            )));
+        }
+        let mut v = self.peek(len)? as i32;
         if v >> (len - 1) == 0 {
             v -= (1 << len) - 1;
         }
-        self.consume(len);
+        self.consume(len)?;
         // tracing::debug!("value: {len} {v}");
+        Ok(v as i16)
+    }
+
+    /// Read `n` bits as an unsigned value.
+    pub fn read_bits(&mut self, n: u8) -> Result<u16> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let v = self.peek(n)?;
+        self.consume(n)?;
         Ok(v)
     }
 
     /// Peek the next 16 bits.
     fn peek_16(&mut self) -> Result<u16> {
         // fast path
-        let buf = self.reader.buffer();
-        if buf.len() >= 2 && buf[0] != 0xFF && buf[1] != 0xFF {
+        let buf = self.reader.fill()?;
+        if buf.len() >= 2 && buf[0] != 0xFF && buf[1] != 0xFF && self.marker.is_none() {
             if self.count < 8 {
                 // read 2 bytes
                 self.buf = (self.buf << 16) | ((buf[0] as u32) << 8) | buf[1] as u32;
                 self.count += 16;
                 self.reader.consume(2);
+                self.count_bytes(2);
                 return Ok((self.buf >> (self.count - 16)) as u16);
             } else if self.count < 16 {
                 // read 1 byte
                 self.buf = (self.buf << 8) | buf[0] as u32;
                 self.count += 8;
                 self.reader.consume(1);
+                self.count_bytes(1);
                 return Ok((self.buf >> (self.count - 16)) as u16);
             }
         }
@@ -240,40 +1562,85 @@ impl<R: Read> BitReader<R> {
             if self.count >= n {
                 break;
             }
-            let b = self.read_byte()?;
-            self.buf = (self.buf << 8) | b as u32;
-            self.count += 8;
+            if self.marker.is_some() {
+                // append 0x00 after the end of segment, but expect not to read it
+                self.buf <<= 8;
+                self.count += 8;
+                self.appended = self.appended.saturating_add(8);
+                continue;
+            }
+            let Some(b) = self.read_byte()? else {
+                // append 0x00 after the end of stream, and fail if it is read
+                self.buf <<= 8;
+                self.count += 8;
+                self.padding += 8;
+                continue;
+            };
             if b == 0xFF {
-                let c = self.read_byte()?;
+                // skip fill bytes
+                let mut c = self.read_byte()?;
+                while c == Some(0xFF) {
+                    c = self.read_byte()?;
+                }
+                let Some(c) = c else {
+                    self.buf <<= 8;
+                    self.count += 8;
+                    self.padding += 8;
+                    continue;
+                };
                 // RSTn (0xDn) or EOI (0xD9)
                 if c != 0 {
-                    // append 0x00, but expect not to read it
+                    self.marker = Some(c);
                     self.buf <<= 8;
                     self.count += 8;
+                    self.appended = 8;
+                    continue;
                 }
             }
+            self.buf = (self.buf << 8) | b as u32;
+            self.count += 8;
         }
         Ok((self.buf >> (self.count - n)) as u16)
     }
 
     /// Consume `n` bits.
-    fn consume(&mut self, n: u8) {
+    fn consume(&mut self, n: u8) -> Result<()> {
+        if n > self.count - self.padding {
+            return Err(JpegError::Truncated);
+        }
         self.count -= n;
         self.buf &= (1 << self.count) - 1;
+        Ok(())
     }
 
-    /// Read a byte. (optimized)
-    fn read_byte(&mut self) -> Result<u8> {
-        let b = self.reader.fill_buf()?[0];
+    /// Read a byte, or `None` at the end of stream. (optimized)
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let Some(&b) = self.reader.fill()?.first() else {
+            return Ok(None);
+        };
         self.reader.consume(1);
-        Ok(b)
+        self.count_bytes(1);
+        Ok(Some(b))
+    }
+
+    #[inline(always)]
+    fn count_bytes(&mut self, n: u64) {
+        self.bytes_read += n;
     }
 }
 
+/// Read the headers of a file, for the tests.
+#[cfg(test)]
+pub(crate) fn open(path: &str) -> McuReader<std::fs::File> {
+    let file = std::fs::File::open(path).expect("failed to read file");
+    crate::Decoder::new(file).read().unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::Decoder;
+    use std::io::{BufReader, Read};
 
     #[test]
     fn test_read_mcu() {
@@ -285,17 +1652,670 @@ mod tests {
     }
 
     #[test]
+    fn test_iterator() {
+        let file = std::fs::File::open("data/city.jpg").expect("failed to read file");
+        let mut reader = Decoder::new(file).read().unwrap();
+        let total = reader.mcu_width_num() as usize * reader.mcu_height_num() as usize;
+        assert_eq!(reader.progress(), (0, total));
+        reader.next().unwrap();
+        assert_eq!(reader.mcu_index(), 1);
+        assert_eq!(reader.by_ref().count(), total - 1);
+        assert_eq!(reader.progress(), (total, total));
+        assert!(Iterator::next(&mut reader).is_none());
+    }
+
+    #[test]
+    fn test_decode_to_rgb8() {
+        let file = std::fs::File::open("data/city.jpg").expect("failed to read file");
+        let decoder = Decoder::new(file);
+        let (width, height, pixels) = decoder.read().unwrap().decode_to_rgb8().unwrap();
+        assert_eq!((width, height), (250, 118));
+        assert_eq!(pixels.len(), 250 * 118);
+    }
+
+    #[test]
+    fn test_decode_rgba8() {
+        let (width, height, rgb) = open("data/city.jpg").decode_to_rgb8().unwrap();
+        let (w, h, rgba) = open("data/city.jpg").decode_rgba8().unwrap();
+        assert_eq!((w, h), (width, height));
+        assert_eq!(rgba.len(), (w * h * 4) as usize);
+        // the corners
+        let (w, h) = (w as usize, h as usize);
+        for (x, y) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)] {
+            let p = rgb[y * w + x];
+            let i = (y * w + x) * 4;
+            assert_eq!(rgba[i..i + 4], [p.r, p.g, p.b, 255]);
+        }
+    }
+
+    #[test]
+    fn test_decode_as() {
+        let (width, height, rgb) = open("data/pattern_420.jpg").decode_to_rgb8().unwrap();
+        // a pixel inside the image
+        let i = 20 * width as usize + 30;
+        let RGB { r, g, b } = rgb[i];
+        for (format, pixel) in [
+            (PixelFormat::Rgb, vec![r, g, b]),
+            (PixelFormat::Bgr, vec![b, g, r]),
+            (PixelFormat::Rgba, vec![r, g, b, 255]),
+            (PixelFormat::Bgra, vec![b, g, r, 255]),
+        ] {
+            let (w, h, bytes) = open("data/pattern_420.jpg").decode_as(format).unwrap();
+            let n = format.bytes_per_pixel();
+            assert_eq!((w, h, bytes.len()), (width, height, rgb.len() * n));
+            assert_eq!(bytes[i * n..(i + 1) * n], pixel, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_cancellable() {
+        let data = std::fs::read("data/autumn.jpg").expect("failed to read file");
+        let open = |data| Decoder::new(data).read().unwrap();
+        let cancel = AtomicBool::new(false);
+        let pixels = open(&data[..]).decode_to_rgb8().unwrap();
+        let cancellable = open(&data[..]).decode_to_rgb8_cancellable(&cancel);
+        assert_eq!(cancellable.unwrap(), pixels);
+
+        // set after a quarter of the stream is read, in small chunks
+        struct Cancel<'a>(&'a [u8], usize, &'a AtomicBool);
+        impl Read for Cancel<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(64);
+                let n = (&self.0[self.1..]).read(&mut buf[..len])?;
+                self.1 += n;
+                if self.1 > self.0.len() / 4 {
+                    self.2.store(true, Ordering::Relaxed);
+                }
+                Ok(n)
+            }
+        }
+        for upsampling in [Upsampling::NearestNeighbor, Upsampling::Bilinear] {
+            cancel.store(false, Ordering::Relaxed);
+            let mut input = Cancel(&data, 0, &cancel);
+            let reader = Decoder::new(&mut input).read().unwrap();
+            let err = (reader.with_upsampling(upsampling))
+                .decode_to_rgb8_cancellable(&cancel)
+                .unwrap_err();
+            assert!(matches!(err, JpegError::Cancelled), "{err}");
+            assert!(
+                input.1 < data.len() / 2,
+                "{upsampling:?}: {} bytes read",
+                input.1
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_into() {
+        for path in ["data/pattern_420.jpg", "data/gray.jpg", "data/restart.jpg"] {
+            let (width, height, pixels) = open(path).decode_to_rgb8().unwrap();
+            let (width, height) = (width as usize, height as usize);
+            let mut out = vec![RGB::default(); width * height];
+            open(path).decode_into(&mut out, width).unwrap();
+            assert!(out == pixels, "{path}");
+
+            // rows in a wider buffer, without the padding after the last one
+            let stride = width + 3;
+            let padding = RGB { r: 1, g: 2, b: 3 };
+            let mut out = vec![padding; stride * (height - 1) + width];
+            open(path).decode_into(&mut out, stride).unwrap();
+            for (y, row) in pixels.chunks_exact(width).enumerate() {
+                assert!(out[y * stride..][..width] == *row, "{path}");
+            }
+            assert_eq!(out[width], padding);
+
+            let err = (open(path).decode_into(&mut out[1..], stride)).unwrap_err();
+            assert!(matches!(err, JpegError::InvalidData(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_crop() {
+        let data = std::fs::read("data/autumn.jpg").expect("failed to read file");
+        let (_, _, pixels) = Decoder::new(&data[..])
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap();
+
+        // 1920x1080 -> 1919x1081, with the same MCUs
+        let mut cropped = data.clone();
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        cropped[sof + 5..sof + 9].copy_from_slice(&[0x04, 0x39, 0x07, 0x7F]);
+        let (width, height, cropped) = (Decoder::new(&cropped[..]).read().unwrap())
+            .decode_to_rgb8()
+            .unwrap();
+        assert_eq!((width, height), (1919, 1081));
+        assert_eq!(cropped.len(), 1919 * 1081);
+        for y in 0..1080 {
+            assert!(cropped[y * 1919..(y + 1) * 1919] == pixels[y * 1920..y * 1920 + 1919]);
+        }
+    }
+
+    #[test]
+    fn test_decode_region() {
+        for path in [
+            "data/pattern_420.jpg",
+            "data/pattern_420_progressive.jpg",
+            "data/orientation.jpg",
+        ] {
+            let (width, _, pixels) = open(path).with_auto_orient(true).decode_to_rgb8().unwrap();
+            for (x, y, w, h) in [
+                (0, 0, 1, 1),
+                (5, 7, 30, 20),
+                (20, 16, 13, 9),
+                (0, 0, width, 45),
+            ] {
+                let expected: Vec<RGB> = (y..y + h)
+                    .flat_map(|y| &pixels[(y * width + x) as usize..(y * width + x + w) as usize])
+                    .copied()
+                    .collect();
+                assert_eq!(
+                    open(path)
+                        .with_auto_orient(true)
+                        .decode_region(x, y, w, h)
+                        .unwrap(),
+                    expected,
+                    "{path}"
+                );
+            }
+        }
+        let file = std::fs::File::open("data/gray.jpg").expect("failed to read file");
+        let reader = Decoder::new(file).read().unwrap();
+        assert!(reader.decode_region(60, 0, 10, 1).is_err());
+    }
+
+    #[test]
+    fn test_pixel_reader() {
+        for path in [
+            "data/pattern_420.jpg",
+            "data/gray.jpg",
+            "data/orientation.jpg",
+        ] {
+            let (_, _, rgba) = open(path).with_auto_orient(true).decode_rgba8().unwrap();
+            let expected: Vec<u8> = rgba
+                .chunks_exact(4)
+                .flat_map(|p| &p[..3])
+                .copied()
+                .collect();
+            let mut bytes = vec![];
+            PixelReader::new(open(path).with_auto_orient(true))
+                .unwrap()
+                .read_to_end(&mut bytes)
+                .unwrap();
+            assert_eq!(bytes, expected, "{path}");
+
+            // in small reads across the rows
+            let mut reader = PixelReader::new(open(path).with_auto_orient(true)).unwrap();
+            let mut buf = [0; 100];
+            let mut bytes = vec![];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => bytes.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(bytes, expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_decode_luma8() {
+        // gray pixels in color, with Cb = Cr = 128
+        let pixels = (0..64 * 48)
+            .map(|i| {
+                let v = (i % 64 * 2 + i / 64 * 3) as u8;
+                RGB { r: v, g: v, b: v }
+            })
+            .collect::<Vec<_>>();
+        let data = crate::encode::Encoder::new(vec![], 90)
+            .encode(64, 48, &pixels)
+            .unwrap();
+        let open = || Decoder::new(&data[..]).read().unwrap();
+        let (_, _, rgb) = open().decode_to_rgb8().unwrap();
+        assert_eq!(
+            open().decode_luma8().unwrap(),
+            (64, 48, rgb.iter().map(|p| p.r).collect())
+        );
+
+        let file = std::fs::File::open("data/city.jpg").expect("failed to read file");
+        let (width, height, luma) = Decoder::new(file).read().unwrap().decode_luma8().unwrap();
+        assert_eq!(luma.len(), width as usize * height as usize);
+    }
+
+    #[test]
+    fn test_decode_planar() {
+        // 67x45 in 4:2:0
+        let planar = open("data/pattern_420.jpg").decode_planar().unwrap();
+        assert_eq!(planar.sizes, [(67, 45), (34, 23), (34, 23)]);
+        assert_eq!((planar.cb.len(), planar.cr.len()), (34 * 23, 34 * 23));
+        let (_, _, luma) = open("data/pattern_420.jpg").decode_luma8().unwrap();
+        assert_eq!(planar.y, luma);
+
+        let planar = open("data/gray.jpg").decode_planar().unwrap();
+        assert_eq!(planar.sizes, [(67, 45), (0, 0), (0, 0)]);
+        assert!(planar.cb.is_empty() && planar.cr.is_empty());
+    }
+
+    #[test]
+    fn test_decode_i420() {
+        let planar = open("data/pattern_420.jpg").decode_planar().unwrap();
+        let (width, height, i420) = open("data/pattern_420.jpg").decode_i420().unwrap();
+        assert_eq!((width, height), (67, 45));
+        let (y, chroma) = (67 * 45, 34 * 23);
+        assert_eq!(i420.len(), y + 2 * chroma);
+        assert_eq!(i420[..y], planar.y);
+        assert_eq!(i420[y..y + chroma], planar.cb);
+        assert_eq!(i420[y + chroma..], planar.cr);
+
+        let err = open("data/gray.jpg").decode_i420().unwrap_err();
+        assert_eq!(err.to_string(), "not 4:2:0 but sampled [(1, 1)]");
+    }
+
+    #[test]
+    fn test_chroma_max_sampling() {
+        // Y 1x1, Cb 2x2 and Cr 1x1
+        let path = "data/pattern_chroma_max.jpg";
+        let (width, height, pixels) = open(path).decode_to_rgb8().unwrap();
+        assert_eq!((width, height), (67, 45));
+        assert_eq!(open(path).decode_scaled(1).unwrap().2, pixels);
+        assert_eq!(open(path).decode_parallel().unwrap(), pixels);
+
+        // the luma upsampled by the nearest sample
+        let (_, _, luma) = open(path).decode_luma8().unwrap();
+        assert_eq!(luma.len(), 67 * 45);
+        let planar = open(path).decode_planar().unwrap();
+        assert_eq!(planar.sizes, [(34, 23), (67, 45), (34, 23)]);
+        for (i, &y) in luma.iter().enumerate() {
+            assert_eq!(y, planar.y[i / 67 / 2 * 34 + i % 67 / 2]);
+        }
+        let bilinear = (open(path).with_upsampling(Upsampling::Bilinear))
+            .decode_to_rgb8()
+            .unwrap();
+        assert!(crate::metrics::psnr(&bilinear.2, &pixels) > 30.0);
+    }
+
+    #[test]
+    fn test_decode_scaled() {
+        for path in ["data/pattern_420.jpg", "data/gray.jpg"] {
+            assert_eq!(
+                open(path).decode_scaled(1).unwrap(),
+                open(path).decode_to_rgb8().unwrap()
+            );
+        }
+        let (width, height, _) = open("data/autumn.jpg").decode_scaled(8).unwrap();
+        assert_eq!((width, height), (240, 135));
+        for denom in [0, 3, 16] {
+            let err = open("data/gray.jpg").decode_scaled(denom).unwrap_err();
+            assert!(matches!(err, JpegError::InvalidData(_)), "{err}");
+        }
+
+        // 67x45 -> 9x6, each pixel close to the average of its block
+        let (_, _, full) = open("data/gray.jpg").decode_to_rgb8().unwrap();
+        let (width, height, scaled) = open("data/gray.jpg").decode_scaled(8).unwrap();
+        assert_eq!((width, height), (9, 6));
+        for (i, p) in scaled.iter().enumerate() {
+            let (x0, y0) = (i % 9 * 8, i / 9 * 8);
+            let block =
+                (y0..(y0 + 8).min(45)).flat_map(|y| (x0..(x0 + 8).min(67)).map(move |x| (x, y)));
+            let (sum, count) = block.fold((0, 0), |(s, c), (x, y)| {
+                (s + full[y * 67 + x].r as u32, c + 1)
+            });
+            // the padding of edge blocks counts in the DC
+            if x0 + 8 <= 67 && y0 + 8 <= 45 {
+                assert!(
+                    (sum / count).abs_diff(p.r as u32) <= 2,
+                    "block {i}: {} vs {}",
+                    sum / count,
+                    p.r
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_dc_preview() {
+        // 250x118 in 4:4:4 -> 32x15
+        let (_, _, full) = open("data/city.jpg").decode_to_rgb8().unwrap();
+        let (width, height, preview) = open("data/city.jpg").decode_dc_preview().unwrap();
+        assert_eq!((width, height), (32, 15));
+        // the blocks without padding, against the average of the full decode
+        for by in 0..118 / 8 {
+            for bx in 0..250 / 8 {
+                let mut sum = [0u32; 3];
+                for y in by * 8..by * 8 + 8 {
+                    for p in &full[y * 250 + bx * 8..][..8] {
+                        sum[0] += p.r as u32;
+                        sum[1] += p.g as u32;
+                        sum[2] += p.b as u32;
+                    }
+                }
+                let p = preview[by * 32 + bx];
+                for (s, v) in sum.iter().zip([p.r, p.g, p.b]) {
+                    assert!((s / 64).abs_diff(v as u32) <= 4, "block ({bx}, {by})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dnl() {
+        let data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        let open = |data| Decoder::new(data).read().unwrap();
+        let (_, _, pixels) = open(&data[..]).decode_to_rgb8().unwrap();
+
+        // height 45 in DNL after the scan instead of SOF
+        let mut dnl = data.clone();
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        dnl[sof + 5..sof + 7].copy_from_slice(&[0, 0]);
+        dnl.splice(
+            dnl.len() - 2..dnl.len() - 2,
+            [0xFF, 0xDC, 0x00, 0x04, 0x00, 0x2D],
+        );
+        let reader = open(&dnl[..]);
+        assert_eq!(reader.height(), 0);
+        assert_eq!(reader.decode_to_rgb8().unwrap(), (67, 45, pixels.clone()));
+        let mut rows = vec![];
+        open(&dnl[..])
+            .for_each_row(|_, row| rows.extend_from_slice(row))
+            .unwrap();
+        assert!(rows == pixels);
+    }
+
+    #[test]
+    fn test_is_complete() {
+        let read_all = |data: &[u8]| {
+            let mut reader = Decoder::new(data).read().unwrap();
+            assert!(!reader.is_complete());
+            while reader.next().unwrap().is_some() {}
+            reader.is_complete()
+        };
+        for path in [
+            "data/pattern_420.jpg",
+            "data/pattern_420_progressive.jpg",
+            "data/pattern_420_noninterleaved.jpg",
+            "data/restart.jpg",
+            "data/gray.jpg",
+            "data/cmyk.jpg",
+        ] {
+            let data = std::fs::read(path).expect("failed to read file");
+            assert!(read_all(&data), "{path}");
+        }
+
+        let data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        // truncated, and EOI in the middle of the scan
+        let mut early = data[..data.len() / 2].to_vec();
+        assert!(!read_all(&early));
+        early.extend([0xFF, 0xD9]);
+        assert!(!read_all(&early));
+        // data left after the final MCU
+        let mut extra = data.clone();
+        extra.splice(data.len() - 2..data.len() - 2, [0x12, 0x34]);
+        assert!(!read_all(&extra));
+    }
+
+    #[test]
+    fn test_error_position() {
+        let mut data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        let mut reader = Decoder::new(&data[..]).read().unwrap();
+        let start = reader.scan_offset().unwrap() as usize;
+        assert_eq!(reader.reader.position(), start as u64);
+        reader.next().unwrap();
+        assert!(reader.reader.position() > start as u64);
+
+        // ones, which are no huffman code
+        let at = start + 100;
+        data.splice(at..at + 8, [0xFF, 0x00].repeat(4));
+        let result = Decoder::new(&data[..]).read().unwrap().decode_to_rgb8();
+        let Err(JpegError::InvalidData(msg)) = result else {
+            panic!("expected an error");
+        };
+        let (_, position) = msg.rsplit_once("at byte 0x").expect(&msg);
+        let position = usize::from_str_radix(position, 16).unwrap();
+        assert!((at..at + 8).contains(&position), "{msg}");
+    }
+
+    #[test]
+    fn test_resume() {
+        // 100x60 in 4:2:0, restarting every 5 MCUs
+        let data = std::fs::read("data/restart.jpg").expect("failed to read file");
+        let mut expected = vec![];
+        let mut reader = Decoder::new(&data[..]).read().unwrap();
+        while let Some(mcu) = reader.next_coefficients().unwrap() {
+            expected.push(mcu);
+        }
+
+        let mut reader = Decoder::new(&data[..]).read().unwrap();
+        let scan = reader.scan_offset().unwrap() as usize;
+        assert_eq!(reader.snapshot().unwrap().offset, scan as u64);
+        for mcu in &expected[..7] {
+            assert_eq!(reader.next_coefficients().unwrap().as_ref(), Some(mcu));
+        }
+        // in the middle of an interval
+        assert_eq!(reader.snapshot(), None);
+        for mcu in &expected[7..10] {
+            assert_eq!(reader.next_coefficients().unwrap().as_ref(), Some(mcu));
+        }
+        let state = reader.snapshot().unwrap();
+        assert_eq!(state.mcu_index, 10);
+        assert_eq!(data[state.offset as usize - 2], 0xFF);
+        drop(reader);
+
+        // the headers and the rest of the stream
+        let rest = [&data[..scan], &data[state.offset as usize..]].concat();
+        let mut reader = Decoder::new(&rest[..]).read().unwrap();
+        assert!(reader
+            .resume(&DecodeState {
+                mcu_index: 3,
+                ..state
+            })
+            .is_err());
+        reader.resume(&state).unwrap();
+        for mcu in &expected[10..] {
+            assert_eq!(reader.next_coefficients().unwrap().as_ref(), Some(mcu));
+        }
+        assert!(reader.next_coefficients().unwrap().is_none());
+        assert!(reader.is_complete());
+        assert_eq!(reader.bytes_consumed(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_for_each_row() {
+        let open = |data| Decoder::new(data).read().unwrap();
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        // the whole image, and a truncated one
+        for data in [&data[..], &data[..5000]] {
+            let (width, height, pixels) = open(data).decode_to_rgb8().unwrap();
+            let mut y = 0;
+            open(data)
+                .for_each_row(|i, row| {
+                    assert_eq!(i, y);
+                    assert!(row == &pixels[i * width as usize..][..width as usize]);
+                    y += 1;
+                })
+                .unwrap();
+            assert_eq!(y, height as usize);
+        }
+    }
+
+    #[test]
+    fn test_decode_parallel() {
+        // with DRI, and without
+        for path in ["data/restart.jpg", "data/city.jpg"] {
+            let (_, _, pixels) = open(path).decode_to_rgb8().unwrap();
+            assert!(open(path).decode_parallel().unwrap() == pixels, "{path}");
+        }
+
+        // invalid huffman codes in the 3rd restart interval, like `test_resync`
+        let mut data = std::fs::read("data/restart.jpg").expect("failed to read file");
+        data[1600..1606].copy_from_slice(b"\xFF\x00\xFF\x00\xFF\x00");
+        let (_, _, pixels) = Decoder::new(&data[..])
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap();
+        let parallel = Decoder::new(&data[..]).read().unwrap().decode_parallel();
+        assert!(parallel.unwrap() == pixels);
+
+        // with the EXIF of data/orientation.jpg, orientation 6
+        let exif = std::fs::read("data/orientation.jpg").expect("failed to read file");
+        let mut data = std::fs::read("data/restart.jpg").expect("failed to read file");
+        data.splice(2..2, exif[2..2 + 36].iter().copied());
+        let open = || {
+            Decoder::new(&data[..])
+                .read()
+                .unwrap()
+                .with_auto_orient(true)
+        };
+        let (width, height, rotated) = open().decode_to_rgb8().unwrap();
+        assert_eq!((width, height), (60, 100));
+        assert!(open().decode_parallel().unwrap() == rotated);
+    }
+
+    #[test]
+    fn test_resync() {
+        let data = std::fs::read("data/restart.jpg").expect("failed to read file");
+        // invalid huffman codes in the 3rd restart interval
+        let mut corrupt = data.clone();
+        corrupt[1600..1606].copy_from_slice(b"\xFF\x00\xFF\x00\xFF\x00");
+
+        let mut reader = Decoder::new(&data[..]).read().unwrap();
+        let mut corrupt = Decoder::new(&corrupt[..]).read().unwrap();
+        let mut count = 0;
+        while let Some(mcu) = corrupt.next().unwrap() {
+            let expected = reader.next().unwrap().unwrap();
+            // 5 MCUs per interval
+            if count / 5 != 2 {
+                assert_eq!(mcu, expected, "MCU {count}");
+            } else if count == 14 {
+                // lost
+                let gray = RGB {
+                    r: 128,
+                    g: 128,
+                    b: 128,
+                };
+                assert!((0..16).flat_map(|h| mcu.line(h)).all(|p| p == gray));
+            }
+            count += 1;
+        }
+        assert_eq!(count, 7 * 4);
+        assert!(!corrupt.truncated());
+    }
+
+    #[test]
+    fn test_zero_run() {
+        // an 8x8 grayscale image, with a single DC code 0 and a single AC code 0xF1
+        let mut data = b"\xFF\xD8\xFF\xDB\x00\x43\x00".to_vec();
+        data.extend([1; 64]);
+        data.extend(b"\xFF\xC0\x00\x0B\x08\x00\x08\x00\x08\x01\x01\x11\x00");
+        for (class, symbol) in [(0x00, 0x00), (0x10, 0xF1)] {
+            data.extend([0xFF, 0xC4, 0x00, 0x14, class, 1]);
+            data.extend([0; 15]);
+            data.push(symbol);
+        }
+        data.extend(b"\xFF\xDA\x00\x08\x01\x01\x00\x00\x3F\x00");
+        // 16 zeros and a coefficient 4 times, from 1 to 65
+        data.extend(b"\x00\x7F\xFF\xD9");
+        let err = Decoder::new(&data[..])
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap_err();
+        assert!(matches!(err, JpegError::InvalidData(_)), "{err}");
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = std::fs::read("data/city.jpg").expect("failed to read file");
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        let total = reader.mcu_width_num() as usize * reader.mcu_height_num() as usize;
+        for len in [2000, 5000, 8000, data.len() - 500] {
+            let mut reader = Decoder::new(&data[..len]).read().unwrap();
+            let mut count = 0;
+            while let Some(_mcu) = reader.next().unwrap() {
+                count += 1;
+            }
+            assert!(reader.truncated(), "cut at {len}");
+            assert!(0 < count && count < total, "cut at {len}: {count} MCUs");
+
+            let reader = Decoder::new(&data[..len]).read().unwrap();
+            let (width, height, pixels) = reader.decode_to_rgb8().unwrap();
+            assert_eq!(pixels.len(), width as usize * height as usize);
+        }
+
+        // missing EOI only
+        let mut reader = Decoder::new(&data[..data.len() - 2]).read().unwrap();
+        assert_eq!(reader.by_ref().count(), total);
+        assert!(!reader.truncated());
+    }
+
+    #[test]
+    fn test_next_coefficients() {
+        let (mut reader, mut coefficients) =
+            (open("data/pattern_420.jpg"), open("data/pattern_420.jpg"));
+        let (sof, qts) = (
+            coefficients.sof.clone(),
+            coefficients.quantization_tables().clone(),
+        );
+        while let Some(mut mcu) = coefficients.next_coefficients().unwrap() {
+            assert_eq!(mcu.blocks.len(), 6);
+            mcu.itrans(&sof, &qts);
+            assert_eq!(Some(mcu.to_rgb(&sof)), reader.next().unwrap());
+        }
+        assert!(reader.next().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_gray_mcu() {
+        let file = std::fs::File::open("data/gray.jpg").expect("failed to read file");
+        let decoder = Decoder::new(file);
+        let mut reader = decoder.read().unwrap();
+        let mut count = 0;
+        while let Some(mcu) = reader.next().unwrap() {
+            assert!((0..8)
+                .flat_map(|h| mcu.line(h))
+                .all(|p| p.r == p.g && p.g == p.b));
+            count += 1;
+        }
+        assert_eq!(count, 9 * 6);
+    }
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn bit_reader() {
         let buf = [0xFF, 0x00, 0b10101010, 0b00000000, 0xFF, 0xAA];
         let mut reader = BitReader::new(BufReader::new(&buf[..]));
         assert_eq!(reader.peek(7).unwrap(), 0b1111111);
         assert_eq!(reader.peek(16).unwrap(), 0b11111111_10101010);
-        reader.consume(4);
+        reader.consume(4).unwrap();
         assert_eq!(reader.peek(16).unwrap(), 0b1111_10101010_0000);
-        reader.consume(4);
+        reader.consume(4).unwrap();
         assert_eq!(reader.peek(16).unwrap(), 0b10101010_00000000);
         assert_eq!(reader.read_value(3).unwrap(), 5);
         assert_eq!(reader.read_value(2).unwrap(), -2);
         assert_eq!(reader.peek(11).unwrap(), 0b010_00000000);
+
+        // fill bytes before a stuffed byte and a marker
+        let buf = [0xFF, 0xFF, 0x00, 0x0F, 0xFF, 0xFF, 0xD9];
+        let mut reader = BitReader::new(BufReader::new(&buf[..]));
+        assert_eq!(reader.read_bits(16).unwrap(), 0xFF0F);
+        assert_eq!(reader.peek(8).unwrap(), 0);
+        assert_eq!(reader.marker(), Some(0xD9));
+    }
+
+    #[test]
+    fn test_unmapped_huffman_code() {
+        // 0 and 10, without any code starting with 11
+        let mut map = HuffmanTree::new();
+        map.insert(0b0, 1, 5);
+        map.insert(0b10, 2, 7);
+        let buf = [0b0101_1000, 0x00];
+        let mut reader = BitReader::new(BufReader::new(&buf[..]));
+        assert_eq!(reader.read_decode_haffman(&map).unwrap(), 5);
+        assert_eq!(reader.read_decode_haffman(&map).unwrap(), 7);
+        let err = reader.read_decode_haffman(&map).unwrap_err();
+        assert!(matches!(err, JpegError::InvalidData(_)), "{err}");
+        // nothing is consumed by the invalid code
+        assert_eq!(reader.peek(2).unwrap(), 0b11);
     }
 }
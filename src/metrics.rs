@@ -0,0 +1,67 @@
+//! Differences between two decoded images, e.g. to compare a decode with a reference.
+
+use crate::RGB;
+
+/// The mean squared error of R, G and B, each in 0..=255 squared.
+///
+/// Panics if the images are not of the same number of pixels.
+pub fn channel_mse(a: &[RGB], b: &[RGB]) -> [f64; 3] {
+    assert_eq!(a.len(), b.len(), "images of different sizes");
+    let mut sum = [0u64; 3];
+    for (p, q) in a.iter().zip(b) {
+        for (s, (x, y)) in sum.iter_mut().zip([(p.r, q.r), (p.g, q.g), (p.b, q.b)]) {
+            *s += (x.abs_diff(y) as u64).pow(2);
+        }
+    }
+    // 0 for empty images
+    let n = a.len().max(1) as f64;
+    sum.map(|s| s as f64 / n)
+}
+
+/// The mean squared error over all channels, like [`channel_mse`].
+pub fn mse(a: &[RGB], b: &[RGB]) -> f64 {
+    channel_mse(a, b).iter().sum::<f64>() / 3.0
+}
+
+/// The peak signal-to-noise ratio of R, G and B in dB, infinite for identical channels.
+pub fn channel_psnr(a: &[RGB], b: &[RGB]) -> [f64; 3] {
+    channel_mse(a, b).map(psnr_of)
+}
+
+/// The peak signal-to-noise ratio over all channels in dB, infinite for identical images.
+pub fn psnr(a: &[RGB], b: &[RGB]) -> f64 {
+    psnr_of(mse(a, b))
+}
+
+fn psnr_of(mse: f64) -> f64 {
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+
+    #[test]
+    fn test_psnr() {
+        let data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        let (_, _, pixels) = Decoder::new(&data[..])
+            .read()
+            .unwrap()
+            .decode_to_rgb8()
+            .unwrap();
+        assert_eq!(mse(&pixels, &pixels), 0.0);
+        assert_eq!(psnr(&pixels, &pixels), f64::INFINITY);
+
+        // one bit off in a single channel
+        let mut other = pixels.clone();
+        other[10].g ^= 1;
+        let n = pixels.len() as f64;
+        assert_eq!(channel_mse(&pixels, &other), [0.0, 1.0 / n, 0.0]);
+        let expected = 10.0 * (255.0 * 255.0 * 3.0 * n).log10();
+        assert!((psnr(&pixels, &other) - expected).abs() < 1e-9);
+        let [r, g, _] = channel_psnr(&pixels, &other);
+        assert_eq!(r, f64::INFINITY);
+        assert!((g - 10.0 * (255.0 * 255.0 * n).log10()).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{BufWriter, Result, Write};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::decode::RGB;
+
+/// Write 8-bit RGB PNG, streaming row by row.
+pub struct PngWriter<W: Write> {
+    writer: BufWriter<W>,
+    encoder: ZlibEncoder<Vec<u8>>,
+    /// The pending bytes of the current row.
+    row: Vec<u8>,
+    width: usize,
+}
+
+impl<W: Write> PngWriter<W> {
+    /// The size of compressed data to flush in an IDAT chunk.
+    const CHUNK_SIZE: usize = 1 << 16;
+
+    pub fn new(writer: W, width: u32, height: u32) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+        let mut ihdr = [0; 13];
+        ihdr[0..4].copy_from_slice(&width.to_be_bytes());
+        ihdr[4..8].copy_from_slice(&height.to_be_bytes());
+        // bit depth 8, color type RGB, deflate, filter method 0, no interlace
+        ihdr[8..].copy_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut writer, b"IHDR", &ihdr)?;
+        Ok(PngWriter {
+            writer,
+            encoder: ZlibEncoder::new(vec![], Compression::default()),
+            row: Vec::with_capacity(width as usize * 3),
+            width: width as usize,
+        })
+    }
+
+    pub fn write_slice(&mut self, pixel: &[RGB]) -> Result<()> {
+        let mut buf = RGB::as_bytes(pixel);
+        while !buf.is_empty() {
+            let len = buf.len().min(self.width * 3 - self.row.len());
+            self.row.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+            if self.row.len() == self.width * 3 {
+                // filter type: None
+                self.encoder.write_all(&[0])?;
+                self.encoder.write_all(&self.row)?;
+                self.row.clear();
+            }
+        }
+        if self.encoder.get_ref().len() >= Self::CHUNK_SIZE {
+            write_chunk(&mut self.writer, b"IDAT", self.encoder.get_ref())?;
+            self.encoder.get_mut().clear();
+        }
+        Ok(())
+    }
+
+    /// Write the remaining data and the end of image.
+    ///
+    /// The PNG is incomplete without calling this.
+    pub fn finish(mut self) -> Result<W> {
+        let data = self.encoder.finish()?;
+        write_chunk(&mut self.writer, b"IDAT", &data)?;
+        write_chunk(&mut self.writer, b"IEND", &[])?;
+        self.writer.flush()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// Write a chunk with its length and CRC.
+fn write_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = !crc32(crc32(!0, kind), data);
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// Update the CRC-32 used by PNG.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xEDB88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    for &b in data {
+        crc = TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_png_writer() {
+        let pixels: Vec<RGB> = (0..6)
+            .map(|i| RGB {
+                r: i,
+                g: 2 * i,
+                b: 3 * i,
+            })
+            .collect();
+        let mut writer = PngWriter::new(vec![], 3, 2).unwrap();
+        // fragments do not need to be aligned with rows
+        writer.write_slice(&pixels[..2]).unwrap();
+        writer.write_slice(&pixels[2..]).unwrap();
+        let png = writer.finish().unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        #[rustfmt::skip]
+        assert_eq!(&png[8..33], &[
+            0, 0, 0, 13, b'I', b'H', b'D', b'R',
+            0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0,
+            0x12, 0x16, 0xF1, 0x4D,
+        ]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+
+        let len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut raw = vec![];
+        flate2::read::ZlibDecoder::new(&png[41..41 + len])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(
+            raw,
+            [0, 0, 0, 0, 1, 2, 3, 2, 4, 6, 0, 3, 6, 9, 4, 8, 12, 5, 10, 15]
+        );
+    }
+}
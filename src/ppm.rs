@@ -1,21 +1,166 @@
 use std::io::{BufWriter, Result, Write};
 
-use crate::decode::RGB;
+use crate::decode::{RGB, RGB16};
 
+/// Write binary PPM (P6) in 8 or 16 bits per channel.
 pub struct PpmWriter<W: Write> {
     writer: BufWriter<W>,
+    /// 255 or 65535.
+    maxval: u16,
 }
 
 impl<W: Write> PpmWriter<W> {
     pub fn new(writer: W, width: u32, height: u32) -> Result<Self> {
+        Self::with_maxval(writer, width, height, 255)
+    }
+
+    /// Write 16 bits per channel by [`Self::write_slice16`].
+    pub fn new_16bit(writer: W, width: u32, height: u32) -> Result<Self> {
+        Self::with_maxval(writer, width, height, 65535)
+    }
+
+    fn with_maxval(writer: W, width: u32, height: u32, maxval: u16) -> Result<Self> {
         let mut writer = BufWriter::new(writer);
-        write!(writer, "P6\n{} {}\n255\n", width, height)?;
-        Ok(PpmWriter { writer })
+        write!(writer, "P6\n{} {}\n{}\n", width, height, maxval)?;
+        Ok(PpmWriter { writer, maxval })
     }
 
+    /// Write 8-bit pixels, one byte per channel.
     pub fn write_slice(&mut self, pixel: &[RGB]) -> Result<()> {
-        let buf =
-            unsafe { std::slice::from_raw_parts(pixel.as_ptr() as *const u8, pixel.len() * 3) };
-        self.writer.write_all(buf)
+        assert_eq!(self.maxval, 255, "8-bit pixels in a 16-bit PPM");
+        self.writer.write_all(RGB::as_bytes(pixel))
+    }
+
+    /// Write 16-bit pixels, each channel in big endian as PPM requires.
+    pub fn write_slice16(&mut self, pixel: &[RGB16]) -> Result<()> {
+        assert_eq!(self.maxval, 65535, "16-bit pixels in an 8-bit PPM");
+        for p in pixel {
+            for c in [p.r, p.g, p.b] {
+                self.writer.write_all(&c.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the whole image of rows of `width` pixels, tightly packed.
+    pub fn write_image(&mut self, width: u32, pixels: &[RGB]) -> Result<()> {
+        let rest = pixels.len().checked_rem(width as usize);
+        assert!(rest.unwrap_or(pixels.len()) == 0, "incomplete row");
+        self.write_slice(pixels)
+    }
+
+    /// Flush the buffer and return the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        // flush the inner writer too, whose errors `into_inner` ignores
+        self.writer.flush()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// Write binary grayscale PGM (P5).
+pub struct PgmWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> PgmWriter<W> {
+    pub fn new(writer: W, width: u32, height: u32) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        write!(writer, "P5\n{} {}\n255\n", width, height)?;
+        Ok(PgmWriter { writer })
+    }
+
+    pub fn write_gray(&mut self, pixel: &[u8]) -> Result<()> {
+        self.writer.write_all(pixel)
+    }
+
+    /// Flush the buffer and return the inner writer, like [`PpmWriter::finish`].
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+
+    #[test]
+    fn test_pgm_writer() {
+        let file = std::fs::File::open("data/gray.jpg").expect("failed to read file");
+        let reader = Decoder::new(file).read().unwrap();
+        assert_eq!(reader.component_number(), 1);
+        let (width, height, pixels) = reader.decode_to_rgb8().unwrap();
+        let gray: Vec<u8> = pixels.iter().map(|p| p.r).collect();
+
+        let mut buf = vec![];
+        let mut writer = PgmWriter::new(&mut buf, width, height).unwrap();
+        writer.write_gray(&gray).unwrap();
+        writer.finish().unwrap();
+        let header = b"P5\n67 45\n255\n";
+        assert_eq!(&buf[..header.len()], header);
+        assert_eq!(buf.len(), header.len() + 67 * 45);
+    }
+
+    #[test]
+    fn test_ppm_writer() {
+        let pixels: Vec<RGB> = (0..6)
+            .map(|i| RGB {
+                r: i,
+                g: 2 * i,
+                b: 3 * i,
+            })
+            .collect();
+        let mut writer = PpmWriter::new(vec![], 3, 2).unwrap();
+        writer.write_image(3, &pixels).unwrap();
+        let buf = writer.finish().unwrap();
+        let header = b"P6\n3 2\n255\n";
+        assert_eq!(&buf[..header.len()], header);
+        assert_eq!(&buf[header.len()..header.len() + 6], [0, 0, 0, 1, 2, 3]);
+        assert_eq!(buf.len(), header.len() + 3 * 6);
+    }
+
+    #[test]
+    fn test_ppm_writer_16bit() {
+        let pixels = [
+            RGB16 {
+                r: 0x0102,
+                g: 0x0304,
+                b: 0x0506,
+            },
+            RGB16::from(RGB { r: 255, g: 1, b: 0 }),
+        ];
+        let mut writer = PpmWriter::new_16bit(vec![], 2, 1).unwrap();
+        writer.write_slice16(&pixels).unwrap();
+        let buf = writer.finish().unwrap();
+        let header = b"P6\n2 1\n65535\n";
+        assert_eq!(&buf[..header.len()], header);
+        assert_eq!(
+            &buf[header.len()..],
+            [1, 2, 3, 4, 5, 6, 0xFF, 0xFF, 1, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_finish_error() {
+        /// A writer which fails to flush.
+        struct FailingFlush(Vec<u8>);
+        impl Write for FailingFlush {
+            fn write(&mut self, buf: &[u8]) -> Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+        }
+
+        let mut writer = PpmWriter::new(FailingFlush(vec![]), 1, 1).unwrap();
+        writer.write_slice(&[RGB { r: 1, g: 2, b: 3 }]).unwrap();
+        let err = writer.finish().err().unwrap();
+        assert_eq!(err.to_string(), "disk full");
+
+        let mut writer = PgmWriter::new(FailingFlush(vec![]), 1, 1).unwrap();
+        writer.write_gray(&[1]).unwrap();
+        assert!(writer.finish().is_err());
     }
 }
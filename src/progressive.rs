@@ -0,0 +1,360 @@
+use crate::{
+    huffman::{HuffmanTables, HuffmanTree},
+    mcu::{BitReader, Block, Mcu},
+    prelude::*,
+    source::ByteSource,
+    start_of_frame_0::StartOfFrameInfo,
+    start_of_scan::StartOfScanInfo,
+    JpegError, Result,
+};
+use tracing::debug;
+
+/// DCT coefficients of a whole frame.
+///
+/// A progressive JPEG spreads the coefficients of every block over multiple scans,
+/// so they are accumulated here before any block can be transformed.
+/// So does a baseline JPEG with each component in a separate, non-interleaved scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coefficients {
+    /// Blocks of each component in raster order, covering whole MCUs.
+    planes: Vec<Vec<Block>>,
+    /// The number of blocks in a row of each component.
+    widths: Vec<usize>,
+    /// Whether the scans are sequential ones of a baseline frame, with the whole spectrum.
+    sequential: bool,
+}
+
+impl Coefficients {
+    pub fn new(sof: &StartOfFrameInfo) -> Self {
+        let mut planes = vec![];
+        let mut widths = vec![];
+        for c in &sof.component_infos {
+            let width = sof.mcu_width_num() as usize * c.horizontal_sampling as usize;
+            let height = sof.mcu_height_num() as usize * c.vertical_sampling as usize;
+            planes.push(vec![Block([0; 64]); width * height]);
+            widths.push(width);
+        }
+        Coefficients {
+            planes,
+            widths,
+            sequential: false,
+        }
+    }
+
+    /// The coefficients of a baseline frame whose scans are not interleaved.
+    pub fn sequential(sof: &StartOfFrameInfo) -> Self {
+        Coefficients {
+            sequential: true,
+            ..Self::new(sof)
+        }
+    }
+
+    /// Get the `index`-th MCU in raster order.
+    pub fn mcu(&self, sof: &StartOfFrameInfo, index: usize) -> Mcu {
+        let mcu_width_num = sof.mcu_width_num() as usize;
+        let (my, mx) = (index / mcu_width_num, index % mcu_width_num);
+        let mut mcu = Mcu::default();
+        for (id, c) in sof.component_infos.iter().enumerate() {
+            let (h, v) = (c.horizontal_sampling as usize, c.vertical_sampling as usize);
+            for y in my * v..(my + 1) * v {
+                for x in mx * h..(mx + 1) * h {
+                    mcu.blocks.push(self.planes[id][y * self.widths[id] + x]);
+                }
+            }
+        }
+        mcu
+    }
+
+    /// Decode a scan and refine the coefficients.
+    pub(crate) fn read_scan<R: ByteSource>(
+        &mut self,
+        reader: &mut BitReader<R>,
+        sof: &StartOfFrameInfo,
+        sos: &StartOfScanInfo,
+        huffman: &HuffmanTables,
+        restart_interval: Option<u16>,
+    ) -> Result<()> {
+        let components = &sos.components[..sos.component_number as usize];
+        let (ah, al) = (sos.ah_al >> 4, sos.ah_al & 0x0F);
+        debug!(?components, sos.ss, sos.se, ah, al, "read scan");
+        if self.sequential {
+            if (sos.ss, sos.se, sos.ah_al) != (0x00, 0x3F, 0x00) {
+                return Err(JpegError::InvalidSos(format!(
+                    "invalid baseline scan: Ss {}, Se {}, Ah/Al 0x{:02X}",
+                    sos.ss, sos.se, sos.ah_al
+                )));
+            }
+        } else if sos.ss > sos.se || sos.se > 63 || (sos.ss == 0 && sos.se != 0) {
+            return Err(JpegError::InvalidSos(format!(
+                "invalid spectral selection: {}..={}",
+                sos.ss, sos.se
+            )));
+        }
+        if sos.ss != 0 && components.len() != 1 {
+            return Err(JpegError::InvalidSos(
+                "AC scan must have a single component".into(),
+            ));
+        }
+        for &c in components {
+            if c >= sof.component_number {
+                return Err(JpegError::InvalidSos(format!(
+                    "component not in frame: {}",
+                    c + 1
+                )));
+            }
+        }
+
+        let mut scan = Scan {
+            reader,
+            ss: sos.ss as usize,
+            se: sos.se as usize,
+            ah,
+            al,
+            last_dc: [0; 4],
+            eob_run: 0,
+        };
+        // the DC and AC tables of each component
+        let mut tables = vec![];
+        for &c in components {
+            let id = sos.table_mapping[c as usize];
+            tables.push(match (sos.ss, sos.se, ah) {
+                (0, 0, 0) => (Some(huffman.get(id.dc)?), None),
+                (0, 0, _) => (None, None),
+                (0, _, _) => (Some(huffman.get(id.dc)?), Some(huffman.get(id.ac)?)),
+                _ => (None, Some(huffman.get(id.ac)?)),
+            });
+        }
+
+        // no restart marker follows the last interval
+        let restart = |scan: &mut Scan<R>, i: usize, total: usize| -> Result<()> {
+            if i != total && matches!(restart_interval, Some(r) if i.is_multiple_of(r as usize)) {
+                scan.reader.reset()?;
+                scan.last_dc = [0; 4];
+                scan.eob_run = 0;
+            }
+            Ok(())
+        };
+        if let &[c] = components {
+            // non-interleaved: one block per MCU, covering only the component itself
+            let c = c as usize;
+            let info = &sof.component_infos[c];
+            let width = ((sof.width as usize * info.horizontal_sampling as usize)
+                .div_ceil(sof.max_horizontal_sampling as usize))
+            .div_ceil(8);
+            let height = ((sof.height as usize * info.vertical_sampling as usize)
+                .div_ceil(sof.max_vertical_sampling as usize))
+            .div_ceil(8);
+            let (table, plane) = (tables[0], &mut self.planes[c]);
+            for y in 0..height {
+                for x in 0..width {
+                    scan.read_block(&mut plane[y * self.widths[c] + x], 0, table)?;
+                    restart(&mut scan, y * width + x + 1, width * height)?;
+                }
+            }
+        } else {
+            let total = sof.mcu_height_num() as usize * sof.mcu_width_num() as usize;
+            for i in 0..total {
+                let (my, mx) = (
+                    i / sof.mcu_width_num() as usize,
+                    i % sof.mcu_width_num() as usize,
+                );
+                for (&c, table) in components.iter().zip(&tables) {
+                    let c = c as usize;
+                    let info = &sof.component_infos[c];
+                    let (h, v) = (
+                        info.horizontal_sampling as usize,
+                        info.vertical_sampling as usize,
+                    );
+                    for y in my * v..(my + 1) * v {
+                        for x in mx * h..(mx + 1) * h {
+                            let block = &mut self.planes[c][y * self.widths[c] + x];
+                            scan.read_block(block, c, *table)?;
+                        }
+                    }
+                }
+                restart(&mut scan, i + 1, total)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State of decoding a scan.
+struct Scan<'a, R: ByteSource> {
+    reader: &'a mut BitReader<R>,
+    ss: usize,
+    se: usize,
+    /// Successive approximation bit position high.
+    ah: u8,
+    /// Successive approximation bit position low.
+    al: u8,
+    last_dc: [i16; 4],
+    /// The number of remaining blocks in an end-of-band run.
+    eob_run: u16,
+}
+
+impl<R: ByteSource> Scan<'_, R> {
+    /// Decode the coefficients of the `id`-th component in a block,
+    /// with the DC and AC tables of the scan.
+    fn read_block(
+        &mut self,
+        block: &mut Block,
+        id: usize,
+        tables: (Option<&HuffmanTree>, Option<&HuffmanTree>),
+    ) -> Result<()> {
+        let x = &mut block.0;
+        match (self.ss, self.ah, tables) {
+            (0, 0, (Some(dc), ac)) => {
+                let len = self.reader.read_decode_haffman(dc)?;
+                let diff = self.reader.read_value(len)?;
+                self.last_dc[id] = self.last_dc[id].wrapping_add(diff);
+                x[0] = self.last_dc[id] << self.al;
+                // a sequential scan of the whole spectrum
+                if let Some(ac) = ac {
+                    self.read_ac_first(x, ac)?;
+                }
+            }
+            (0, _, _) => {
+                if self.reader.read_bits(1)? == 1 {
+                    x[0] |= 1 << self.al;
+                }
+            }
+            (_, 0, (_, Some(ac))) => self.read_ac_first(x, ac)?,
+            (_, _, (_, Some(ac))) => self.read_ac_refine(x, ac)?,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// The first scan of a spectral band.
+    fn read_ac_first(&mut self, x: &mut [i16; 64], ac: &HuffmanTree) -> Result<()> {
+        if self.eob_run > 0 {
+            self.eob_run -= 1;
+            return Ok(());
+        }
+        // from 1 after the DC of a sequential scan
+        let mut i = self.ss.max(1);
+        while i <= self.se {
+            let code = self.reader.read_decode_haffman(ac)?;
+            let (run, len) = (code >> 4, code & 0x0F);
+            if len == 0 {
+                if run < 15 {
+                    // end of band, in this and the following `eob_run` blocks
+                    self.eob_run = (1 << run) - 1 + self.reader.read_bits(run)?;
+                    break;
+                }
+                i += 16;
+                continue;
+            }
+            i += run as usize;
+            if i > self.se {
+                return Err(JpegError::InvalidData(format!(
+                    "AC coefficient out of band at byte 0x{:X}",
+                    self.reader.position()
+                )));
+            }
+            x[i] = self.reader.read_value(len)? << self.al;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// A successive approximation scan of a spectral band.
+    fn read_ac_refine(&mut self, x: &mut [i16; 64], ac: &HuffmanTree) -> Result<()> {
+        let bit = 1 << self.al;
+        let mut i = self.ss;
+        if self.eob_run == 0 {
+            while i <= self.se {
+                let code = self.reader.read_decode_haffman(ac)?;
+                let (mut run, len) = (code >> 4, code & 0x0F);
+                let value = match len {
+                    0 if run < 15 => {
+                        self.eob_run = (1 << run) + self.reader.read_bits(run)?;
+                        break;
+                    }
+                    0 => 0,
+                    1 if self.reader.read_bits(1)? == 1 => bit,
+                    1 => -bit,
+                    _ => {
+                        return Err(JpegError::InvalidData(format!(
+                            "invalid AC refinement code {code} at byte 0x{:X}",
+                            self.reader.position()
+                        )))
+                    }
+                };
+                // skip `run` zero coefficients, refining the non-zero ones on the way
+                while i <= self.se {
+                    if x[i] != 0 {
+                        self.refine(&mut x[i], bit)?;
+                    } else if run == 0 {
+                        if value != 0 {
+                            x[i] = value;
+                        }
+                        i += 1;
+                        break;
+                    } else {
+                        run -= 1;
+                    }
+                    i += 1;
+                }
+            }
+        }
+        if self.eob_run > 0 {
+            // refine the remaining non-zero coefficients in the band
+            for v in &mut x[i..=self.se] {
+                if *v != 0 {
+                    self.refine(v, bit)?;
+                }
+            }
+            self.eob_run -= 1;
+        }
+        Ok(())
+    }
+
+    /// Read a correction bit for a non-zero coefficient.
+    fn refine(&mut self, v: &mut i16, bit: i16) -> Result<()> {
+        if self.reader.read_bits(1)? == 1 && *v & bit == 0 {
+            *v += if *v >= 0 { bit } else { -bit };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mcu::open, Decoder};
+
+    #[test]
+    fn test_progressive() {
+        // the same image encoded in baseline and progressive mode
+        let mut baseline = open("data/pattern_420.jpg");
+        let mut progressive = open("data/pattern_420_progressive.jpg");
+        while let Some(mcu) = baseline.next().unwrap() {
+            assert_eq!(progressive.next().unwrap(), Some(mcu));
+        }
+        assert_eq!(progressive.next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_non_interleaved() {
+        // the same image with the components in one scan and in three scans
+        let decode = |data: &[u8]| Decoder::new(data).read().unwrap().decode_to_rgb8().unwrap();
+        let interleaved = std::fs::read("data/pattern_420.jpg").unwrap();
+        let separate = std::fs::read("data/pattern_420_noninterleaved.jpg").unwrap();
+        assert_eq!(decode(&separate), decode(&interleaved));
+
+        // cut in the middle of the second scan
+        let reader = Decoder::new(&separate[..1400]).read().unwrap();
+        assert!(reader.truncated());
+        assert_eq!(reader.decode_to_rgb8().unwrap().0, 67);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = std::fs::read("data/pattern_420_progressive.jpg").expect("failed to read file");
+        let mut reader = Decoder::new(&data[..data.len() / 2]).read().unwrap();
+        assert!(reader.truncated());
+        let total = reader.mcu_width_num() as usize * reader.mcu_height_num() as usize;
+        assert_eq!(reader.by_ref().count(), total);
+    }
+}
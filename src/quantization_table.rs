@@ -1,18 +1,18 @@
-use super::{error, Decoder};
-use std::{
-    fmt::Debug,
-    io::{Read, Result},
-};
+use crate::decode::ZIGZAG;
+use crate::tables::{scale_quantization_table, STD_CHROMA_QUANT, STD_LUMA_QUANT};
+use crate::{prelude::*, source::Input, Decoder, JpegError, Result};
+use core::fmt::Debug;
 use tracing::debug;
 
 #[derive(Clone, PartialEq, Eq)]
 pub struct QuantizationTable {
     pub id: u8,
-    pub values: [i16; 64],
+    /// Unsigned values in zigzag order, up to 16 bits.
+    pub values: [u16; 64],
 }
 
 impl Debug for QuantizationTable {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         for i in 0..8 {
             for j in 0..8 {
                 write!(f, " {}", self.values[i * 8 + j])?;
@@ -23,41 +23,93 @@ impl Debug for QuantizationTable {
     }
 }
 
-impl<R: Read> Decoder<R> {
+impl QuantizationTable {
+    /// The standard luminance table with id 0, scaled by `quality` in 1..=100 like libjpeg.
+    pub fn standard_luma(quality: u8) -> Self {
+        Self::standard(0, &STD_LUMA_QUANT, quality)
+    }
+
+    /// The standard chrominance table with id 1, scaled by `quality` in 1..=100 like libjpeg.
+    pub fn standard_chroma(quality: u8) -> Self {
+        Self::standard(1, &STD_CHROMA_QUANT, quality)
+    }
+
+    fn standard(id: u8, table: &[u8; 64], quality: u8) -> Self {
+        let table = scale_quantization_table(table, quality.clamp(1, 100));
+        QuantizationTable {
+            id,
+            values: table.map(u16::from),
+        }
+    }
+
+    /// The values in natural order, for [`Block::idct_dequant`].
+    ///
+    /// [`Block::idct_dequant`]: crate::mcu::Block::idct_dequant
+    pub fn in_natural_order(&self) -> [u16; 64] {
+        core::array::from_fn(|i| self.values[ZIGZAG[i]])
+    }
+
+    /// Estimate the libjpeg quality from 1 to 100,
+    /// by the closest standard luminance or chrominance table scaled by it.
+    pub fn estimated_quality(&self) -> u8 {
+        let distance = |quality| {
+            let distance = |table| -> u32 {
+                let table = scale_quantization_table(table, quality);
+                (table.iter().zip(&self.values))
+                    .map(|(&a, &b)| (a as u16).abs_diff(b) as u32)
+                    .sum()
+            };
+            distance(&STD_LUMA_QUANT).min(distance(&STD_CHROMA_QUANT))
+        };
+        // the highest one if some qualities give the same table
+        (1..=100).rev().min_by_key(|&q| distance(q)).unwrap()
+    }
+}
+
+impl<R: Input> Decoder<R> {
     /// Read the [`QuantizationTable`].
     pub fn read_quantization_table(&mut self) -> Result<Vec<QuantizationTable>> {
-        let mut len = self.read_u16()?;
+        let len = self.read_u16()?;
         debug!(len, "read section DQT");
 
-        len -= 2;
+        let invalid_len = || JpegError::InvalidDqt(format!("invalid DQT length: {len}"));
+        let mut rest = len.checked_sub(2).ok_or_else(invalid_len)?;
         let mut tables = vec![];
-        while len != 0 {
+        while rest != 0 {
             let byte = self.read_byte()?;
             let precision = byte >> 4;
             let id = byte & 0x0F;
             debug!(id, precision, "read quantization table");
+            if id > 3 {
+                return Err(JpegError::InvalidDqt(format!("Invalid table id: {id}")));
+            }
             match precision {
                 0 => {
                     let mut values = [0; 64];
                     for v in &mut values {
-                        *v = self.read_byte()? as i16;
+                        *v = self.read_byte()? as u16;
                     }
                     let table = QuantizationTable { id, values };
                     debug!("\n{table:?}");
                     tables.push(table);
-                    len -= 1 + 64;
+                    rest = rest.checked_sub(1 + 64).ok_or_else(invalid_len)?;
                 }
                 1 => {
                     let mut values = [0; 64];
                     for v in &mut values {
-                        *v = self.read_u16()? as i16;
+                        *v = self.read_u16()?;
                     }
                     let table = QuantizationTable { id, values };
                     debug!("\n{table:?}");
                     tables.push(table);
-                    len -= 1 + 128;
+                    rest = rest.checked_sub(1 + 128).ok_or_else(invalid_len)?;
+                }
+                _ => {
+                    return Err(JpegError::InvalidDqt(format!(
+                        "Invalid precision: {}",
+                        precision
+                    )))
                 }
-                _ => return Err(error(format!("Invalid precision: {}", precision))),
             }
         }
         Ok(tables)
@@ -66,7 +118,7 @@ impl<R: Read> Decoder<R> {
 
 #[cfg(test)]
 mod tests {
-    use crate::marker::Marker;
+    use crate::{marker::Marker, mcu::open};
 
     use super::*;
 
@@ -96,5 +148,72 @@ mod tests {
                 ]
             }]
         );
+        assert_eq!(dqts[0].estimated_quality(), 92);
+    }
+
+    #[test]
+    fn test_16bit_table() {
+        let mut data = b"\xFF\xDB\x00\x83\x10".to_vec();
+        data.extend([0x9C, 0x40]); // 40000
+        data.extend([0x03, 0xE8].repeat(63)); // 1000
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(decoder.next_marker().unwrap(), Marker::DQT);
+        let dqt = decoder.read_quantization_table().unwrap();
+        assert_eq!(dqt[0].values[..2], [40000, 1000]);
+
+        // saturate rather than wrap around
+        let mut block = crate::mcu::Block([2; 64]);
+        block.0[1] = -1000;
+        let block = block.dequantize(&dqt[0].values);
+        assert_eq!(block.0[..3], [i16::MAX, i16::MIN, 2000]);
+    }
+
+    #[test]
+    fn test_table_order() {
+        // the tables of pattern_420.jpg in the order of id 1, 0
+        let reader = open("data/dqt_order.jpg");
+        let ids = reader
+            .quantization_tables()
+            .each_ref()
+            .map(|qt| qt.as_ref().map(|qt| qt.id));
+        assert_eq!(ids, [Some(0), Some(1), None, None]);
+        assert_eq!(
+            reader.decode_to_rgb8().unwrap(),
+            open("data/pattern_420.jpg").decode_to_rgb8().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_standard_tables() {
+        let luma = QuantizationTable::standard_luma(50);
+        assert_eq!(luma.id, 0);
+        assert_eq!(luma.in_natural_order(), STD_LUMA_QUANT.map(u16::from));
+        let chroma = QuantizationTable::standard_chroma(50);
+        assert_eq!(chroma.id, 1);
+        assert_eq!(chroma.in_natural_order(), STD_CHROMA_QUANT.map(u16::from));
+        for qt in [
+            QuantizationTable::standard_luma(100),
+            QuantizationTable::standard_chroma(100),
+        ] {
+            assert_eq!(qt.values, [1; 64]);
+        }
+        assert_eq!(QuantizationTable::standard_luma(0).values[0], 255);
+        assert_eq!(
+            QuantizationTable::standard_chroma(75).estimated_quality(),
+            75
+        );
+    }
+
+    #[test]
+    fn test_estimated_quality() {
+        for quality in [10, 30, 50, 75, 95] {
+            let pixels = [crate::RGB::default(); 64];
+            let encoder = crate::encode::Encoder::new(vec![], quality);
+            let jpeg = encoder.encode(8, 8, &pixels).unwrap();
+            let reader = Decoder::new(&jpeg[..]).read().unwrap();
+            for qt in reader.quantization_tables().iter().flatten() {
+                assert_eq!(qt.estimated_quality(), quality);
+            }
+        }
     }
 }
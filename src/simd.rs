@@ -0,0 +1,189 @@
+//! Block transforms with SIMD.
+#![cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+
+use crate::{decode::ZIGZAG, mcu::Block};
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use {
+    crate::decode::{dequantize_one, IDCT},
+    core::arch::x86_64::*,
+};
+
+/// Whether the CPU supports `$feature`, enabled at compile time or detected at runtime,
+/// which needs the `std` feature.
+#[cfg(all(target_arch = "x86_64", feature = "std"))]
+macro_rules! x86_feature {
+    ($feature:tt) => {
+        cfg!(target_feature = $feature) || std::is_x86_feature_detected!($feature)
+    };
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+macro_rules! x86_feature {
+    ($feature:tt) => {
+        cfg!(target_feature = $feature)
+    };
+}
+
+/// Like [`x86_feature`] on AArch64.
+#[cfg(all(target_arch = "aarch64", feature = "std"))]
+macro_rules! aarch64_feature {
+    ($feature:tt) => {
+        cfg!(target_feature = $feature) || std::arch::is_aarch64_feature_detected!($feature)
+    };
+}
+
+#[cfg(all(target_arch = "aarch64", not(feature = "std")))]
+macro_rules! aarch64_feature {
+    ($feature:tt) => {
+        cfg!(target_feature = $feature)
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64_feature;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use x86_feature;
+
+/// The transposed IDCT matrix in i32.
+#[cfg(target_arch = "x86_64")]
+const IDCT_T: [[i32; 8]; 8] = {
+    let mut m = [[0; 8]; 8];
+    let mut i = 0;
+    while i < 8 {
+        let mut j = 0;
+        while j < 8 {
+            m[j][i] = IDCT[i][j] as i32;
+            j += 1;
+        }
+        i += 1;
+    }
+    m
+};
+
+/// [`Block::idct`] with AVX2, giving the same result as the scalar version.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn idct_avx2(block: &Block) -> Block {
+    idct_avx2_with(|k| block.0[k] as i32)
+}
+
+/// [`Block::idct_dequant`] with AVX2, giving the same result as the scalar version.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn idct_dequant_avx2(block: &Block, qt: &[u16; 64]) -> Block {
+    let coef: [i32; 64] = core::array::from_fn(|k| dequantize_one(block.0[ZIGZAG[k]], qt[k]));
+    idct_avx2_with(|k| coef[k])
+}
+
+/// [`Block::idct`] of the coefficients in natural order given by `coef`,
+/// inlined into the callers with AVX2 enabled.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn idct_avx2_with(coef: impl Fn(usize) -> i32) -> Block {
+    let idct = &IDCT;
+    let idct_t = &IDCT_T;
+    let cols = idct_t.map(|c| _mm256_loadu_si256(c.as_ptr() as *const __m256i));
+
+    // 1D IDCT of the rows, transposed: lane `j` of `rows[i]` is the `j`-th output of row `i`
+    let mut rows = [_mm256_setzero_si256(); 8];
+    for i in 0..8 {
+        let mut v = _mm256_setzero_si256();
+        for x in 0..8 {
+            let a = _mm256_set1_epi32(coef(i * 8 + x));
+            v = _mm256_add_epi32(v, _mm256_mullo_epi32(a, cols[x]));
+        }
+        rows[i] = v;
+    }
+
+    // 1D IDCT of the columns
+    let mut res = [_mm256_setzero_si256(); 8];
+    for i in 0..8 {
+        let mut v = _mm256_setzero_si256();
+        for x in 0..8 {
+            let m = _mm256_set1_epi32(idct[i][x] as i32);
+            v = _mm256_add_epi32(v, _mm256_mullo_epi32(rows[x], m));
+        }
+        // (v + (1 << 21)) >> 22, rounded to the nearest
+        res[i] = _mm256_srai_epi32::<22>(_mm256_add_epi32(v, _mm256_set1_epi32(1 << 21)));
+    }
+
+    let mut out = Block([0; 64]);
+    for i in (0..8).step_by(2) {
+        // pack two rows, then fix the order of 64-bit lanes
+        let packed = _mm256_packs_epi32(res[i], res[i + 1]);
+        let packed = _mm256_permute4x64_epi64::<0b11_01_10_00>(packed);
+        _mm256_store_si256(out.0.as_mut_ptr().add(i * 8) as *mut __m256i, packed);
+    }
+    out
+}
+
+/// [`Block::dequantize_zigzag`] with SSE2, giving the same result as the scalar version.
+///
+/// # Safety
+///
+/// The CPU must support SSE2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+pub(crate) unsafe fn dequantize_zigzag_sse2(block: &Block, qt: &[u16; 64]) -> Block {
+    let mut x = [0i16; 64];
+    for k in (0..64).step_by(8) {
+        let a = _mm_loadu_si128(block.0.as_ptr().add(k) as *const __m128i);
+        let q = _mm_loadu_si128(qt.as_ptr().add(k) as *const __m128i);
+        // the 32-bit products of signed `a` and unsigned `q`, in the low and high halves:
+        // `mulhi` takes `q` as signed, which is `q - 65536` if the top bit is set
+        let lo = _mm_mullo_epi16(a, q);
+        let hi = _mm_add_epi16(
+            _mm_mulhi_epi16(a, q),
+            _mm_and_si128(_mm_srai_epi16::<15>(q), a),
+        );
+        // saturate to i16
+        let v = _mm_packs_epi32(_mm_unpacklo_epi16(lo, hi), _mm_unpackhi_epi16(lo, hi));
+        _mm_storeu_si128(x.as_mut_ptr().add(k) as *mut __m128i, v);
+    }
+    unzigzag(&x)
+}
+
+/// [`Block::dequantize_zigzag`] with NEON, giving the same result as the scalar version.
+///
+/// # Safety
+///
+/// The CPU must support NEON.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+pub(crate) unsafe fn dequantize_zigzag_neon(block: &Block, qt: &[u16; 64]) -> Block {
+    let mut x = [0i16; 64];
+    for k in (0..64).step_by(8) {
+        let a = vld1q_s16(block.0.as_ptr().add(k));
+        let q = vld1q_u16(qt.as_ptr().add(k));
+        // the products of i16 and u16 fit in i32
+        let lo = vmulq_s32(
+            vmovl_s16(vget_low_s16(a)),
+            vreinterpretq_s32_u32(vmovl_u16(vget_low_u16(q))),
+        );
+        let hi = vmulq_s32(vmovl_high_s16(a), vreinterpretq_s32_u32(vmovl_high_u16(q)));
+        vst1q_s16(
+            x.as_mut_ptr().add(k),
+            vcombine_s16(vqmovn_s32(lo), vqmovn_s32(hi)),
+        );
+    }
+    unzigzag(&x)
+}
+
+/// Reorder from zigzag order to natural order like [`Block::zigzag`].
+fn unzigzag(x: &[i16; 64]) -> Block {
+    let mut out = Block([0; 64]);
+    for i in 0..64 {
+        out.0[i] = x[ZIGZAG[i]];
+    }
+    out
+}
@@ -0,0 +1,153 @@
+//! The input of the decoder.
+//!
+//! With the default `std` feature, [`Decoder`](crate::Decoder) reads from any [`std::io::Read`]
+//! through a buffer. Without it, the input is a [`ByteSource`], such as `&[u8]`.
+
+use crate::{JpegError, Result};
+
+/// A buffered source of bytes, the minimal IO needed for decoding.
+pub trait ByteSource {
+    /// Return the buffered bytes, reading more if it is empty,
+    /// or an empty slice at the end of the source.
+    fn fill(&mut self) -> Result<&[u8]>;
+
+    /// Mark `n` bytes of [`Self::fill`] as read.
+    fn consume(&mut self, n: usize);
+
+    /// Read a byte, or [`JpegError::Truncated`] at the end of the source.
+    fn read_byte(&mut self) -> Result<u8> {
+        let &b = self.fill()?.first().ok_or(JpegError::Truncated)?;
+        self.consume(1);
+        Ok(b)
+    }
+
+    /// Fill `buf` exactly, or [`JpegError::Truncated`] at the end of the source.
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let data = self.fill()?;
+            if data.is_empty() {
+                return Err(JpegError::Truncated);
+            }
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            self.consume(n);
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::BufRead + ?Sized> ByteSource for T {
+    fn fill(&mut self) -> Result<&[u8]> {
+        Ok(self.fill_buf()?)
+    }
+
+    fn consume(&mut self, n: usize) {
+        std::io::BufRead::consume(self, n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        Ok(std::io::Read::read_exact(self, buf)?)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSource for &[u8] {
+    fn fill(&mut self) -> Result<&[u8]> {
+        Ok(self)
+    }
+
+    fn consume(&mut self, n: usize) {
+        *self = &self[n..];
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: ByteSource + ?Sized> ByteSource for &mut S {
+    fn fill(&mut self) -> Result<&[u8]> {
+        (**self).fill()
+    }
+
+    fn consume(&mut self, n: usize) {
+        (**self).consume(n)
+    }
+}
+
+/// The input of [`Decoder`](crate::Decoder): [`std::io::Read`] with the `std` feature,
+/// or [`ByteSource`] without it, or [`Slice`] in either case.
+pub trait Input {
+    /// The input as a [`ByteSource`], in a [`std::io::BufReader`] for a reader.
+    #[cfg(feature = "std")]
+    type Source: std::io::BufRead;
+    /// The input as a [`ByteSource`], itself without the `std` feature.
+    #[cfg(not(feature = "std"))]
+    type Source: ByteSource;
+
+    fn into_source(self) -> Self::Source;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Input for R {
+    type Source = std::io::BufReader<R>;
+
+    fn into_source(self) -> Self::Source {
+        std::io::BufReader::new(self)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: ByteSource> Input for S {
+    type Source = S;
+
+    fn into_source(self) -> Self::Source {
+        self
+    }
+}
+
+/// Bytes in memory as an [`Input`], read in place instead of copied through a buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Slice<'a>(pub &'a [u8]);
+
+impl<'a> Input for Slice<'a> {
+    type Source = &'a [u8];
+
+    fn into_source(self) -> Self::Source {
+        self.0
+    }
+}
+
+/// A file mapped into memory as an [`Input`], read in place like a [`Slice`].
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct Mmap(pub memmap2::Mmap);
+
+#[cfg(feature = "mmap")]
+impl Input for Mmap {
+    type Source = std::io::Cursor<memmap2::Mmap>;
+
+    fn into_source(self) -> Self::Source {
+        std::io::Cursor::new(self.0)
+    }
+}
+
+/// An [`Input`] as a [`ByteSource`].
+pub type Buffered<R> = <R as Input>::Source;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_source() {
+        let mut source: &[u8] = &[1, 2, 3, 4];
+        assert_eq!(source.read_byte().unwrap(), 1);
+        let mut buf = [0; 2];
+        ByteSource::read_exact(&mut source, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert!(matches!(
+            ByteSource::read_exact(&mut source, &mut buf),
+            Err(JpegError::Truncated)
+        ));
+    }
+}
@@ -1,6 +1,5 @@
-use crate::{error, Decoder};
-use num_enum::TryFromPrimitive;
-use std::io::{Read, Result};
+use crate::{prelude::*, source::Input, Decoder, JpegError, Result};
+use smallvec::SmallVec;
 use tracing::debug;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -8,13 +7,18 @@ pub struct StartOfFrameInfo {
     pub precision: u8,
     pub height: u16,
     pub width: u16,
-    pub component_infos: [ComponentInfo; 3], // [Y, Cb, Cr]
+    /// The number of components: 1 for grayscale, 3 for YCbCr, 4 for CMYK or YCCK.
+    pub component_number: u8,
+    /// The components in the order of the frame header.
+    pub component_infos: SmallVec<[ComponentInfo; 4]>,
     pub max_horizontal_sampling: u8,
     pub max_vertical_sampling: u8,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct ComponentInfo {
+    /// The component id as read, referred to by the scan headers.
+    pub id: u8,
     pub horizontal_sampling: u8,
     pub vertical_sampling: u8,
     pub quant_table_id: u8,
@@ -29,60 +33,131 @@ impl StartOfFrameInfo {
         8 * self.max_vertical_sampling as u16
     }
 
+    /// 0 for a width of 0.
     pub fn mcu_width_num(&self) -> u16 {
-        (self.width - 1) / self.mcu_width() + 1
+        self.width.div_ceil(self.mcu_width())
     }
 
+    /// 0 until the height is defined by DNL.
     pub fn mcu_height_num(&self) -> u16 {
-        (self.height - 1) / self.mcu_height() + 1
+        self.height.div_ceil(self.mcu_height())
+    }
+
+    /// The index of the component with `id` in [`Self::component_infos`].
+    pub fn component_index(&self, id: u8) -> Option<usize> {
+        self.component_infos.iter().position(|c| c.id == id)
+    }
+
+    /// The number of blocks of all components in an interleaved MCU.
+    pub fn blocks_per_mcu(&self) -> usize {
+        self.component_infos
+            .iter()
+            .map(|c| c.blocks_per_mcu())
+            .sum()
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
-#[repr(u8)]
-pub enum Component {
-    Y = 1,
-    Cb = 2,
-    Cr = 3,
+impl ComponentInfo {
+    /// The horizontal and the vertical sampling factors.
+    pub fn sampling_factor(&self) -> (u8, u8) {
+        (self.horizontal_sampling, self.vertical_sampling)
+    }
+
+    /// The number of blocks of this component in an interleaved MCU.
+    pub fn blocks_per_mcu(&self) -> usize {
+        self.horizontal_sampling as usize * self.vertical_sampling as usize
+    }
 }
 
-impl<R: Read> Decoder<R> {
+impl<R: Input> Decoder<R> {
     /// Read the Start Of Frame 0 (baseline) info.
     pub fn read_start_of_frame_0(&mut self) -> Result<StartOfFrameInfo> {
         let len = self.read_u16()?;
         debug!(len, "read section SOF0");
+        self.read_frame_header()
+    }
+
+    /// Read the Start Of Frame 2 (progressive) info.
+    ///
+    /// The frame header has the same layout as the baseline one.
+    pub fn read_start_of_frame_2(&mut self) -> Result<StartOfFrameInfo> {
+        let len = self.read_u16()?;
+        debug!(len, "read section SOF2");
+        self.read_frame_header()
+    }
 
+    fn read_frame_header(&mut self) -> Result<StartOfFrameInfo> {
         let precision = self.read_byte()?;
+        if precision != 8 {
+            return Err(JpegError::UnsupportedPrecision(precision));
+        }
         let height = self.read_u16()?;
         let width = self.read_u16()?;
-        let number_of_component = self.read_byte()?;
+        let component_number = self.read_byte()?;
+        if !matches!(component_number, 1 | 3 | 4) {
+            return Err(JpegError::InvalidSof(format!(
+                "unsupported component number: {component_number}"
+            )));
+        }
 
-        let mut component_infos = [ComponentInfo::default(); 3];
-        for _ in 0..number_of_component {
-            let component_id = self.read_byte()?;
-            Component::try_from(component_id)
-                .map_err(|_| error(format!("invalid component id: {}", component_id)))?;
+        let mut component_infos = SmallVec::<[ComponentInfo; 4]>::new();
+        for _ in 0..component_number {
+            let id = self.read_byte()?;
+            if component_infos.iter().any(|c| c.id == id) {
+                return Err(JpegError::InvalidSof(format!(
+                    "duplicate component id: {id}"
+                )));
+            }
             let sampling = self.read_byte()?;
+            let (h, v) = (sampling >> 4, sampling & 0x0f);
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+                return Err(JpegError::InvalidSof(format!(
+                    "invalid sampling factor of component {id}: {h}x{v}"
+                )));
+            }
             let quant_table_id = self.read_byte()?;
-            component_infos[component_id as usize - 1] = ComponentInfo {
-                horizontal_sampling: sampling >> 4,
-                vertical_sampling: sampling & 0x0f,
+            component_infos.push(ComponentInfo {
+                id,
+                horizontal_sampling: h,
+                vertical_sampling: v,
                 quant_table_id,
-            };
+            });
+        }
+        if component_number == 1 {
+            // a single-component scan is never interleaved: one block per MCU
+            let c = &mut component_infos[0];
+            c.horizontal_sampling = 1;
+            c.vertical_sampling = 1;
+        }
+        if width == 0 {
+            return Err(JpegError::InvalidSof("zero width".into()));
+        }
+        let max_horizontal_sampling = (component_infos.iter())
+            .map(|c| c.horizontal_sampling)
+            .max()
+            .unwrap();
+        let max_vertical_sampling = (component_infos.iter())
+            .map(|c| c.vertical_sampling)
+            .max()
+            .unwrap();
+        // each component is upsampled by integer factors to the max, which may be the chroma's
+        for c in &component_infos {
+            let (h, v) = (c.horizontal_sampling, c.vertical_sampling);
+            if max_horizontal_sampling % h != 0 || max_vertical_sampling % v != 0 {
+                return Err(JpegError::InvalidSof(format!(
+                    "unsupported sampling factor of component {}: {h}x{v}",
+                    c.id
+                )));
+            }
         }
 
         Ok(StartOfFrameInfo {
             precision,
             height,
             width,
-            max_horizontal_sampling: (component_infos.iter())
-                .map(|c| c.horizontal_sampling)
-                .max()
-                .unwrap(),
-            max_vertical_sampling: (component_infos.iter())
-                .map(|c| c.vertical_sampling)
-                .max()
-                .unwrap(),
+            component_number,
+            max_horizontal_sampling,
+            max_vertical_sampling,
             component_infos,
         })
     }
@@ -109,18 +184,22 @@ mod tests {
                 precision: 8,
                 height: 1080,
                 width: 1920,
-                component_infos: [
+                component_number: 3,
+                component_infos: smallvec::smallvec![
                     ComponentInfo {
+                        id: 1,
                         horizontal_sampling: 2,
                         vertical_sampling: 2,
                         quant_table_id: 0,
                     },
                     ComponentInfo {
+                        id: 2,
                         horizontal_sampling: 1,
                         vertical_sampling: 1,
                         quant_table_id: 1,
                     },
                     ComponentInfo {
+                        id: 3,
                         horizontal_sampling: 1,
                         vertical_sampling: 1,
                         quant_table_id: 1,
@@ -131,4 +210,91 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_blocks_per_mcu() {
+        let file = std::fs::File::open("data/pattern_420.jpg").expect("failed to read file");
+        let mut decoder = Decoder::new(file);
+        while decoder.next_marker().unwrap() != Marker::SOF0 {}
+        let mut sof = decoder.read_start_of_frame_0().unwrap();
+        assert_eq!(sof.component_infos[0].sampling_factor(), (2, 2));
+        let blocks: Vec<_> = (sof.component_infos.iter())
+            .map(|c| c.blocks_per_mcu())
+            .collect();
+        assert_eq!(blocks, [4, 1, 1]);
+        assert_eq!(sof.blocks_per_mcu(), 6);
+
+        // 4:4:4
+        sof.component_infos[0].horizontal_sampling = 1;
+        sof.component_infos[0].vertical_sampling = 1;
+        assert_eq!(sof.component_infos[0].blocks_per_mcu(), 1);
+        assert_eq!(sof.blocks_per_mcu(), 3);
+    }
+
+    #[test]
+    fn test_invalid_sampling_factor() {
+        let data = std::fs::read("data/pattern_420.jpg").expect("failed to read file");
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        // the sampling byte of the second component
+        let at = sof + 10 + 3 + 1;
+        assert_eq!(data[at], 0x11);
+        for (sampling, expected) in [
+            (0x01, "invalid sampling factor of component 2: 0x1"),
+            (0x10, "invalid sampling factor of component 2: 1x0"),
+            (0x51, "invalid sampling factor of component 2: 5x1"),
+        ] {
+            let mut data = data.clone();
+            data[at] = sampling;
+            let err = Decoder::new(&data[..]).read().err().unwrap();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_zero_dimensions() {
+        let mut data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        data[sof + 5..sof + 9].fill(0);
+        let err = Decoder::new(&data[..]).read().err().unwrap();
+        assert_eq!(err.to_string(), "zero width");
+        assert!(Decoder::new(&data[..]).probe().is_err());
+
+        let mut decoder = Decoder::new(std::fs::File::open("data/gray.jpg").unwrap());
+        while decoder.next_marker().unwrap() != Marker::SOF0 {}
+        let mut sof0 = decoder.read_start_of_frame_0().unwrap();
+        sof0.width = 0;
+        sof0.height = 0;
+        assert_eq!((sof0.mcu_width_num(), sof0.mcu_height_num()), (0, 0));
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        // 12-bit
+        data[sof + 4] = 12;
+        let err = Decoder::new(&data[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::UnsupportedPrecision(12)), "{err}");
+        assert_eq!(err.to_string(), "unsupported sample precision: 12 bits");
+    }
+
+    #[test]
+    fn test_component_ids() {
+        // renumber the components 1, 2, 3 to 0, 1, 2 like some encoders do
+        let original = std::fs::read("data/city.jpg").expect("failed to read file");
+        let mut data = original.clone();
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        for i in 0..3 {
+            data[sof + 10 + 3 * i] -= 1;
+        }
+        let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        for i in 0..3 {
+            data[sos + 5 + 2 * i] -= 1;
+        }
+        let reader = Decoder::new(&data[..]).read().unwrap();
+        let ids: Vec<_> = reader.component_infos().iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        let decode = |data: &[u8]| Decoder::new(data).read().unwrap().decode_to_rgb8().unwrap();
+        assert_eq!(decode(&data), decode(&original));
+    }
 }
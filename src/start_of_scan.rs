@@ -1,15 +1,26 @@
 use crate::{
-    error,
     huffman::HuffmanTableClass::{self, *},
-    start_of_frame_0::Component,
-    Decoder,
+    prelude::*,
+    source::Input,
+    start_of_frame_0::StartOfFrameInfo,
+    Decoder, JpegError, Result,
 };
-use std::io::{Read, Result};
 use tracing::debug;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StartOfScanInfo {
-    pub table_mapping: [HuffmanTableId; 3], // [Y, Cb, Cr]
+    /// The tables of each component, by the index in the frame.
+    pub table_mapping: [HuffmanTableId; 4],
+    /// The number of components in this scan.
+    pub component_number: u8,
+    /// Index of the components in the frame, in the order they are interleaved.
+    pub components: [u8; 4],
+    /// Start of spectral selection.
+    pub ss: u8,
+    /// End of spectral selection.
+    pub se: u8,
+    /// Successive approximation bit position high (upper 4 bits) and low (lower 4 bits).
+    pub ah_al: u8,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,40 +29,57 @@ pub struct HuffmanTableId {
     pub ac: HuffmanTableClass,
 }
 
-impl<R: Read> Decoder<R> {
-    /// Read the [`StartOfScanInfo`].
-    pub fn read_start_of_scan(&mut self) -> Result<StartOfScanInfo> {
+impl<R: Input> Decoder<R> {
+    /// Read the [`StartOfScanInfo`], mapping the component ids to the components in `sof`.
+    pub fn read_start_of_scan(&mut self, sof: &StartOfFrameInfo) -> Result<StartOfScanInfo> {
         let len = self.read_u16()?;
         debug!(len, "read section SOS");
 
-        let mut table_mapping = [HuffmanTableId { dc: DC0, ac: AC0 }; 3];
+        let mut table_mapping = [HuffmanTableId { dc: DC0, ac: AC0 }; 4];
 
         let component_number = self.read_byte()?;
-        assert_eq!(component_number, 3);
-        for _ in 0..component_number {
+        if !(1..=4).contains(&component_number) {
+            return Err(JpegError::InvalidSos(format!(
+                "unsupported component number: {component_number}"
+            )));
+        }
+        let mut components = [0; 4];
+        for i in 0..component_number as usize {
             let component_id = self.read_byte()?;
-            Component::try_from(component_id)
-                .map_err(|_| error(format!("invalid component id: {}", component_id)))?;
+            let index = sof.component_index(component_id).ok_or_else(|| {
+                JpegError::InvalidSos(format!("invalid component id: {}", component_id))
+            })?;
+            components[i] = index as u8;
             let id = self.read_byte()?;
-            table_mapping[component_id as usize - 1] = HuffmanTableId {
+            table_mapping[index] = HuffmanTableId {
                 dc: match id >> 4 {
                     0 => DC0,
                     1 => DC1,
-                    dc => return Err(error(format!("invalid DC table: {dc}"))),
+                    dc => return Err(JpegError::InvalidSos(format!("invalid DC table: {dc}"))),
                 },
                 ac: match id & 0x0F {
                     0 => AC0,
                     1 => AC1,
-                    ac => return Err(error(format!("invalid AC table: {ac}"))),
+                    ac => return Err(JpegError::InvalidSos(format!("invalid AC table: {ac}"))),
                 },
             };
         }
-        // skip 3 bytes
-        assert_eq!(self.read_byte()?, 0x00);
-        assert_eq!(self.read_byte()?, 0x3F);
-        assert_eq!(self.read_byte()?, 0x00);
+        let ss = self.read_byte()?;
+        let se = self.read_byte()?;
+        let ah_al = self.read_byte()?;
+        debug!(
+            ss,
+            se, ah_al, "spectral selection and successive approximation"
+        );
 
-        Ok(StartOfScanInfo { table_mapping })
+        Ok(StartOfScanInfo {
+            table_mapping,
+            component_number,
+            components,
+            ss,
+            se,
+            ah_al,
+        })
     }
 }
 
@@ -66,8 +94,12 @@ mod tests {
         // tracing_subscriber::fmt::init();
         let file = std::fs::File::open("data/autumn.jpg").expect("failed to read file");
         let mut decoder = Decoder::new(file);
+        while decoder.next_marker().expect("failed to read marker") != Marker::SOF0 {}
+        let sof = decoder.read_start_of_frame_0().unwrap();
         while decoder.next_marker().expect("failed to read marker") != Marker::SOS {}
-        let sos = decoder.read_start_of_scan().expect("failed to read SOS");
+        let sos = decoder
+            .read_start_of_scan(&sof)
+            .expect("failed to read SOS");
         assert_eq!(
             sos,
             StartOfScanInfo {
@@ -75,8 +107,28 @@ mod tests {
                     HuffmanTableId { dc: DC0, ac: AC0 },
                     HuffmanTableId { dc: DC1, ac: AC1 },
                     HuffmanTableId { dc: DC1, ac: AC1 },
-                ]
+                    HuffmanTableId { dc: DC0, ac: AC0 },
+                ],
+                component_number: 3,
+                components: [0, 1, 2, 0],
+                ss: 0x00,
+                se: 0x3F,
+                ah_al: 0x00,
             }
         );
     }
+
+    #[test]
+    fn test_baseline_scan() {
+        // Ss, Se and Ah/Al of a progressive scan in a baseline frame
+        let mut data = std::fs::read("data/gray.jpg").expect("failed to read file");
+        let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        data[sos + 7..sos + 10].copy_from_slice(&[0x01, 0x05, 0x01]);
+        let err = Decoder::new(&data[..]).read().err().unwrap();
+        assert!(matches!(err, JpegError::InvalidSos(_)), "{err}");
+        assert_eq!(
+            err.to_string(),
+            "invalid baseline scan: Ss 1, Se 5, Ah/Al 0x01"
+        );
+    }
 }
@@ -0,0 +1,60 @@
+//! Per-stage timing of decoding, with the `stats` feature.
+
+/// The time spent in each stage and the amount of data decoded by a [`McuReader`].
+///
+/// Each stage reads the clock twice per MCU, which itself costs a noticeable share
+/// of the decoding time of small MCUs, so enable the feature only to profile.
+///
+/// [`McuReader`]: crate::mcu::McuReader
+#[cfg(feature = "stats")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Entropy decoding of the coefficients, including the scans of a progressive frame.
+    pub huffman_ns: u64,
+    /// Dequantization and inverse DCT.
+    pub idct_ns: u64,
+    /// Upsampling and color conversion.
+    pub color_ns: u64,
+    /// The bytes of entropy-coded data.
+    pub bytes_read: u64,
+    /// The number of MCUs decoded.
+    pub mcus: u64,
+}
+
+/// Evaluate `$e`, adding the nanoseconds elapsed to `$ns` with the `stats` feature.
+///
+/// The clock is read twice, so time a whole MCU rather than a block.
+macro_rules! timed {
+    ($ns:expr, $e:expr) => {{
+        #[cfg(feature = "stats")]
+        let start = std::time::Instant::now();
+        let result = $e;
+        #[cfg(feature = "stats")]
+        {
+            $ns += start.elapsed().as_nanos() as u64;
+        }
+        result
+    }};
+}
+
+pub(crate) use timed;
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use crate::Decoder;
+
+    #[test]
+    fn test_stats() {
+        for path in ["data/city.jpg", "data/pattern_420_progressive.jpg"] {
+            let data = std::fs::read(path).expect("failed to read file");
+            let mut reader = Decoder::from_bytes(&data).read().unwrap();
+            assert_eq!(reader.stats().mcus, 0);
+            let total = reader.mcu_width_num() as u64 * reader.mcu_height_num() as u64;
+            while reader.next().unwrap().is_some() {}
+            let stats = reader.stats();
+            assert_eq!(stats.mcus, total, "{path}");
+            assert!(stats.huffman_ns > 0 && stats.idct_ns > 0 && stats.color_ns > 0);
+            assert!(stats.bytes_read > 0 && stats.bytes_read < data.len() as u64);
+        }
+    }
+}
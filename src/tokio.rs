@@ -0,0 +1,235 @@
+//! Decoding from an [`AsyncRead`] of [`tokio`](::tokio).
+//!
+//! The segments are read asynchronously into a buffer, and the MCUs are decoded from it
+//! synchronously once enough data is buffered, so a slow source never blocks the decoding.
+
+use crate::{mcu::McuReader, Decoder, McuRGB, Result};
+use ::tokio::io::{AsyncRead, AsyncReadExt};
+use std::{
+    collections::VecDeque,
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+/// The bytes buffered ahead of the decoding, more than the largest MCU with byte stuffing
+/// and the buffer of [`std::io::BufReader`].
+const MARGIN: usize = 32 << 10;
+
+/// A JPEG decoder reading from an [`AsyncRead`], like [`Decoder`].
+pub struct AsyncDecoder<R: AsyncRead + Unpin> {
+    reader: R,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        AsyncDecoder { reader }
+    }
+
+    /// Read the headers like [`Decoder::read`].
+    ///
+    /// A progressive image, or one with non-interleaved scans or a DNL, is buffered until
+    /// the end, because all of its scans are decoded before the first MCU.
+    pub async fn read(mut self) -> Result<AsyncMcuReader<R>> {
+        let mut head = vec![];
+        let whole = read_headers(&mut self.reader, &mut head).await?;
+        let shared = Arc::new(Mutex::new(Shared {
+            data: head.into(),
+            eof: false,
+        }));
+        let mut reader = AsyncMcuReader {
+            input: self.reader,
+            shared: shared.clone(),
+            chunk: vec![0; 8 << 10],
+            reader: None,
+        };
+        reader.fill(if whole { usize::MAX } else { MARGIN }).await?;
+        reader.reader = Some(Decoder::new(Buffered { shared }).read()?);
+        Ok(reader)
+    }
+}
+
+/// Read MCUs like [`McuReader`], reading the entropy-coded data asynchronously.
+pub struct AsyncMcuReader<R: AsyncRead + Unpin> {
+    input: R,
+    shared: Arc<Mutex<Shared>>,
+    /// The buffer of each read from `input`.
+    chunk: Vec<u8>,
+    /// Always `Some` after [`AsyncDecoder::read`].
+    reader: Option<McuReader<Buffered>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncMcuReader<R> {
+    /// The synchronous reader of the buffered data, for the dimensions and the metadata.
+    pub fn reader(&self) -> &McuReader<Buffered> {
+        self.reader.as_ref().unwrap()
+    }
+
+    /// Read a minimum coded unit (MCU) like [`McuReader::next`].
+    pub async fn next(&mut self) -> Result<Option<McuRGB>> {
+        self.fill(MARGIN).await?;
+        self.reader.as_mut().unwrap().next()
+    }
+
+    /// Read from the input until `len` bytes are buffered, or the end of it.
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        loop {
+            {
+                let shared = self.shared.lock().unwrap();
+                if shared.eof || shared.data.len() >= len {
+                    return Ok(());
+                }
+            }
+            let n = self.input.read(&mut self.chunk).await?;
+            let mut shared = self.shared.lock().unwrap();
+            shared.eof = n == 0;
+            shared.data.extend(&self.chunk[..n]);
+        }
+    }
+}
+
+/// The bytes read from the input but not by the decoder yet.
+struct Shared {
+    data: VecDeque<u8>,
+    /// Whether the input has ended.
+    eof: bool,
+}
+
+/// The blocking [`Read`] of the data buffered by [`AsyncMcuReader`].
+pub struct Buffered {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Read for Buffered {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.shared.lock().unwrap().data.read(buf)
+    }
+}
+
+/// Read the segments into `head` until SOS or EOI, returning whether all the scans are read
+/// by [`Decoder::read`]: those of a progressive frame, of a frame with non-interleaved scans
+/// and of one with its height in DNL.
+///
+/// Stop early at malformed data, which is left to [`Decoder::read`].
+async fn read_headers(reader: &mut (impl AsyncRead + Unpin), head: &mut Vec<u8>) -> Result<bool> {
+    let mut whole = false;
+    // the number of components of the frame
+    let mut components = 0;
+    let mut pos = 0;
+    loop {
+        if !fill_to(reader, head, pos + 4).await? || head[pos] != 0xFF {
+            return Ok(whole);
+        }
+        let marker = head[pos + 1];
+        pos += 2;
+        match marker {
+            // fill bytes
+            0xFF => pos -= 1,
+            // markers without a segment: TEM, RSTn and SOI
+            0x01 | 0xD0..=0xD8 => {}
+            // EOI
+            0xD9 => return Ok(whole),
+            _ => {
+                let len = u16::from_be_bytes([head[pos], head[pos + 1]]) as usize;
+                let segment = pos;
+                pos += len;
+                if !fill_to(reader, head, pos).await? {
+                    return Ok(whole);
+                }
+                match marker {
+                    // SOFn, but DHT, JPG and DAC
+                    0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) && len >= 8 => {
+                        let height = u16::from_be_bytes([head[segment + 3], head[segment + 4]]);
+                        components = head[segment + 7];
+                        whole |= marker == 0xC2 || height == 0;
+                    }
+                    // SOS
+                    0xDA => {
+                        let scan_components = if len >= 3 { head[segment + 2] } else { 0 };
+                        return Ok(whole || scan_components < components);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Read into `head` until it has `len` bytes, returning `false` at the end of `reader`.
+async fn fill_to(
+    reader: &mut (impl AsyncRead + Unpin),
+    head: &mut Vec<u8>,
+    len: usize,
+) -> Result<bool> {
+    while head.len() < len {
+        let n = (&mut *reader)
+            .take((len - head.len()) as u64)
+            .read_to_end(head)
+            .await?;
+        if n == 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// An input returning at most 100 bytes for each read, and pending every other time.
+    struct Slow<'a> {
+        data: &'a [u8],
+        pending: bool,
+    }
+
+    impl AsyncRead for Slow<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ::tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = self.data.len().min(buf.remaining()).min(100);
+            buf.put_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_async_decoder() {
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for path in [
+            "data/exif.jpg",
+            "data/city.jpg",
+            "data/pattern_420_progressive.jpg",
+            // non-interleaved scans beyond `MARGIN`
+            "data/pattern_420_noninterleaved_large.jpg",
+        ] {
+            let data = std::fs::read(path).expect("failed to read file");
+            let mut expected = Decoder::from_bytes(&data).read().unwrap();
+            runtime.block_on(async {
+                let input = Slow {
+                    data: &data,
+                    pending: false,
+                };
+                let mut reader = AsyncDecoder::new(input).read().await.unwrap();
+                assert_eq!(reader.reader().width(), expected.width());
+                while let Some(mcu) = reader.next().await.unwrap() {
+                    assert_eq!(Some(mcu), expected.next().unwrap(), "{path}");
+                }
+                assert_eq!(expected.next().unwrap(), None);
+            });
+        }
+    }
+}